@@ -0,0 +1,45 @@
+use postfix_policy::{SessionPolicyRequestHandler, PolicyResponse, handle_connection_sessioned};
+use std::os::unix::net::UnixListener;
+use std::thread;
+use std::fs::remove_file;
+
+const MAX_RECIPIENTS: usize = 10;
+
+/// Rejects every recipient after the first `MAX_RECIPIENTS` of a message.
+/// Has to be used for smtpd_recipient_restrictions so it sees every RCPT TO.
+struct RecipientLimiter<'l> {
+    max_recipients: &'l usize,
+    recipient_count: usize,
+}
+
+impl<'l> SessionPolicyRequestHandler<'l, usize, ()> for RecipientLimiter<'l> {
+    fn new(max_recipients: &'l usize) -> Self { Self{
+        max_recipients,
+        recipient_count: 0,
+    }}
+    fn attribute(&mut self, name: &[u8], value: &[u8]) -> Option<()> {
+        // recipient_count only counts the already accepted recipients of the current message
+        if name == b"recipient_count" {
+            self.recipient_count = std::str::from_utf8(value).ok().and_then(|v| v.parse().ok()).unwrap_or(0);
+        }
+        None
+    }
+    fn response(&mut self, protocol_state: &[u8]) -> Result<PolicyResponse, ()> {
+        if protocol_state == b"RCPT" && self.recipient_count >= *self.max_recipients {
+            return Ok(PolicyResponse::defer_str(format!("4.5.3 Too many recipients, at most {} are allowed", self.max_recipients)));
+        }
+        Ok(PolicyResponse::Dunno)
+    }
+}
+
+fn main() {
+    remove_file("/tmp/policy_max_recipients").ok();
+    let listener = UnixListener::bind("/tmp/policy_max_recipients").expect("Binding listener socket failed");
+
+    for client in listener.incoming() {
+        thread::spawn(move || {
+            let client = client.expect("Something failed while listening");
+            handle_connection_sessioned::<RecipientLimiter, _, _, _>(&client, &MAX_RECIPIENTS).expect("handling connection failed");
+        });
+    }
+}
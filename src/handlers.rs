@@ -0,0 +1,1264 @@
+//! Reusable [`PolicyRequestHandler`] implementations and adapters wrapping other handlers.
+//!
+//! [`PolicyRequestHandler`]: ../trait.PolicyRequestHandler.html
+
+use super::{
+    normalize_and_parse_ip, parse_u64, Clock, ParsedRequest, PolicyRequestHandler, PolicyResponse, ProtocolState,
+    SystemClock,
+};
+use std::collections::HashMap;
+use std::io::{Error as IoError, Write};
+use std::net::IpAddr;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// One request seen by a [`RecordingHandler`] together with the response the inner handler produced.
+///
+/// [`RecordingHandler`]: struct.RecordingHandler.html
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedRequest {
+    pub request: ParsedRequest,
+    pub response: PolicyResponse,
+}
+
+/// Context of a [`RecordingHandler`], holding the context of the inner handler and the recorded transcript.
+///
+/// [`RecordingHandler`]: struct.RecordingHandler.html
+#[derive(Debug, Default)]
+pub struct RecordingContext<ContextType> {
+    /// The context passed to the inner handler.
+    pub inner: ContextType,
+    transcript: Mutex<Vec<RecordedRequest>>,
+}
+
+impl<ContextType> RecordingContext<ContextType> {
+    /// Creates a new context with an empty transcript, passing `inner` to the inner handler.
+    pub fn new(inner: ContextType) -> Self {
+        Self {
+            inner,
+            transcript: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns the requests recorded so far, in the order they were answered.
+    pub fn transcript(&self) -> Vec<RecordedRequest> {
+        self.transcript.lock().unwrap().clone()
+    }
+}
+
+/// Wraps the handler `HandlerType`, recording every request and the response the inner handler produced for it
+/// in the [`RecordingContext`]. Useful for snapshot testing complex handlers.
+///
+/// Requests for which the inner handler returned an error are not recorded.
+///
+/// [`RecordingContext`]: struct.RecordingContext.html
+pub struct RecordingHandler<'l, HandlerType, ContextType> {
+    inner: HandlerType,
+    ctx: &'l RecordingContext<ContextType>,
+    request: ParsedRequest,
+}
+
+impl<'l, HandlerType, ContextType, ErrorType> PolicyRequestHandler<'l, RecordingContext<ContextType>, ErrorType>
+    for RecordingHandler<'l, HandlerType, ContextType>
+where
+    HandlerType: PolicyRequestHandler<'l, ContextType, ErrorType>,
+{
+    fn new(ctx: &'l RecordingContext<ContextType>) -> Self {
+        Self {
+            inner: HandlerType::new(&ctx.inner),
+            ctx,
+            request: ParsedRequest::new(),
+        }
+    }
+
+    fn attribute(&mut self, name: &[u8], value: &[u8]) -> Option<ErrorType> {
+        self.request.push(name, value);
+        self.inner.attribute(name, value)
+    }
+
+    fn raw_line(&mut self, line: &[u8]) {
+        self.inner.raw_line(line)
+    }
+
+    fn response(self) -> Result<PolicyResponse, ErrorType> {
+        let response = self.inner.response()?;
+        self.ctx.transcript.lock().unwrap().push(RecordedRequest {
+            request: self.request,
+            response: response.clone(),
+        });
+        Ok(response)
+    }
+}
+
+/// Context of a [`TimeoutHandler`], holding the context of the inner handler and the timeout configuration.
+///
+/// [`TimeoutHandler`]: struct.TimeoutHandler.html
+#[derive(Debug)]
+pub struct TimeoutContext<ContextType> {
+    /// The context passed to the inner handler.
+    pub inner: ContextType,
+    /// How long the inner handler's `response` may take.
+    pub deadline: Duration,
+    /// Sent instead of the inner handler's response if it doesn't finish within `deadline`.
+    pub fallback: PolicyResponse,
+}
+
+/// Wraps the handler `HandlerType`, bounding the time its `response` may take so Postfix doesn't run into its
+/// own timeout while waiting for the policy server.
+///
+/// The inner handler's `response` is run on a newly spawned thread. If it doesn't finish within
+/// [`TimeoutContext::deadline`], [`TimeoutContext::fallback`] is returned instead. The thread is not cancelled
+/// then: it keeps running in the background until `response` returns, and its result is discarded. This is why the
+/// inner handler and its error type have to be `Send + 'static`, so the handler can't borrow from its context.
+/// A panic in the inner handler is propagated to the caller.
+///
+/// [`TimeoutContext::deadline`]: struct.TimeoutContext.html#structfield.deadline
+/// [`TimeoutContext::fallback`]: struct.TimeoutContext.html#structfield.fallback
+pub struct TimeoutHandler<'l, HandlerType, ContextType> {
+    inner: HandlerType,
+    ctx: &'l TimeoutContext<ContextType>,
+}
+
+impl<'l, HandlerType, ContextType, ErrorType> PolicyRequestHandler<'l, TimeoutContext<ContextType>, ErrorType>
+    for TimeoutHandler<'l, HandlerType, ContextType>
+where
+    HandlerType: PolicyRequestHandler<'l, ContextType, ErrorType> + Send + 'static,
+    ErrorType: Send + 'static,
+{
+    fn new(ctx: &'l TimeoutContext<ContextType>) -> Self {
+        Self {
+            inner: HandlerType::new(&ctx.inner),
+            ctx,
+        }
+    }
+
+    fn attribute(&mut self, name: &[u8], value: &[u8]) -> Option<ErrorType> {
+        self.inner.attribute(name, value)
+    }
+
+    fn raw_line(&mut self, line: &[u8]) {
+        self.inner.raw_line(line)
+    }
+
+    fn response(self) -> Result<PolicyResponse, ErrorType> {
+        let (sender, receiver) = mpsc::channel();
+        let inner = self.inner;
+        let worker = thread::spawn(move || {
+            // the receiver is gone if the deadline passed already
+            sender.send(inner.response()).ok();
+        });
+        match receiver.recv_timeout(self.ctx.deadline) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => Ok(self.ctx.fallback.clone()),
+            Err(RecvTimeoutError::Disconnected) => match worker.join() {
+                Err(panic) => std::panic::resume_unwind(panic),
+                Ok(()) => unreachable!("worker finished without sending a result"),
+            },
+        }
+    }
+}
+
+/// Context of a [`ChainHandler`], holding the contexts of both handlers.
+///
+/// [`ChainHandler`]: struct.ChainHandler.html
+#[derive(Debug, Clone)]
+pub struct ChainContext<FirstContextType, SecondContextType> {
+    /// The context passed to the first handler.
+    pub first: FirstContextType,
+    /// The context passed to the second handler.
+    pub second: SecondContextType,
+}
+
+/// Combines two handlers like two restrictions following each other in Postfix' restriction list.
+///
+/// Both handlers get all attributes. If the first handler's response is terminal (see
+/// `PolicyResponse::is_terminal`), it is returned and the second handler's `response` isn't called. Otherwise the
+/// second handler decides, unless it responds `DUNNO`: then the first handler's response (e.g. a `WARN` or `HOLD`) is
+/// kept, as only one action can be sent per request. Nest `ChainContext`s to chain more than two handlers.
+pub struct ChainHandler<FirstType, SecondType> {
+    first: FirstType,
+    second: SecondType,
+}
+
+impl<'l, FirstType, SecondType, FirstContextType, SecondContextType, ErrorType>
+    PolicyRequestHandler<'l, ChainContext<FirstContextType, SecondContextType>, ErrorType>
+    for ChainHandler<FirstType, SecondType>
+where
+    FirstType: PolicyRequestHandler<'l, FirstContextType, ErrorType>,
+    SecondType: PolicyRequestHandler<'l, SecondContextType, ErrorType>,
+{
+    fn new(ctx: &'l ChainContext<FirstContextType, SecondContextType>) -> Self {
+        Self {
+            first: FirstType::new(&ctx.first),
+            second: SecondType::new(&ctx.second),
+        }
+    }
+
+    fn attribute(&mut self, name: &[u8], value: &[u8]) -> Option<ErrorType> {
+        self.first
+            .attribute(name, value)
+            .or_else(|| self.second.attribute(name, value))
+    }
+
+    fn raw_line(&mut self, line: &[u8]) {
+        self.first.raw_line(line);
+        self.second.raw_line(line);
+    }
+
+    fn response(self) -> Result<PolicyResponse, ErrorType> {
+        let first = self.first.response()?;
+        if first.is_terminal() {
+            return Ok(first);
+        }
+        match self.second.response()? {
+            PolicyResponse::Dunno => Ok(first),
+            second => Ok(second),
+        }
+    }
+}
+
+/// Attribute name prefixes redacted by [`RedactingDumpContext::new`]: the SASL authentication and client
+/// certificate attributes.
+///
+/// [`RedactingDumpContext::new`]: struct.RedactingDumpContext.html#method.new
+pub const DEFAULT_SENSITIVE_PREFIXES: &[&[u8]] = &[b"sasl_", b"ccert_"];
+
+/// Context of a [`RedactingDumpHandler`], holding the output and the attributes to redact.
+///
+/// [`RedactingDumpHandler`]: struct.RedactingDumpHandler.html
+#[derive(Debug)]
+pub struct RedactingDumpContext<WriterType> {
+    /// The values of all attributes whose name starts with one of these are replaced by `***` in the output.
+    pub sensitive_prefixes: Vec<Vec<u8>>,
+    /// If non-empty, written as a line of its own before every request, e.g. to tell apart the requests of
+    /// concurrent connections dumped to the same output.
+    pub header: Vec<u8>,
+    output: Mutex<WriterType>,
+}
+
+impl<WriterType: Write> RedactingDumpContext<WriterType> {
+    /// Creates a new context writing to `output`, redacting the attributes in [`DEFAULT_SENSITIVE_PREFIXES`].
+    ///
+    /// [`DEFAULT_SENSITIVE_PREFIXES`]: constant.DEFAULT_SENSITIVE_PREFIXES.html
+    pub fn new(output: WriterType) -> Self {
+        Self::with_sensitive_prefixes(output, DEFAULT_SENSITIVE_PREFIXES.iter().map(|p| p.to_vec()).collect())
+    }
+
+    /// Creates a new context writing to `output`, redacting the attributes starting with one of `sensitive_prefixes`.
+    pub fn with_sensitive_prefixes(output: WriterType, sensitive_prefixes: Vec<Vec<u8>>) -> Self {
+        Self {
+            sensitive_prefixes,
+            header: Vec::new(),
+            output: Mutex::new(output),
+        }
+    }
+
+    /// Sets the `header` written before every request, see [`RedactingDumpContext::header`].
+    ///
+    /// [`RedactingDumpContext::header`]: struct.RedactingDumpContext.html#structfield.header
+    pub fn with_header(mut self, header: impl Into<Vec<u8>>) -> Self {
+        self.header = header.into();
+        self
+    }
+
+    /// Consumes the context, returning the output.
+    pub fn into_output(self) -> WriterType {
+        self.output.into_inner().unwrap()
+    }
+}
+
+/// Writes every request to the output of its [`RedactingDumpContext`] and answers it with `DUNNO`.
+/// Useful to see what Postfix sends without leaking passwords or certificate details into logs.
+///
+/// Each request is written as one block of `name=value` lines followed by an empty line, preceded by the context's
+/// `header` line if it has one. The values of sensitive
+/// attributes are replaced by `***`, empty values are kept so it stays visible the attribute was empty.
+///
+/// [`RedactingDumpContext`]: struct.RedactingDumpContext.html
+pub struct RedactingDumpHandler<'l, WriterType> {
+    ctx: &'l RedactingDumpContext<WriterType>,
+    output: Vec<u8>,
+}
+
+impl<'l, WriterType: Write> PolicyRequestHandler<'l, RedactingDumpContext<WriterType>, IoError>
+    for RedactingDumpHandler<'l, WriterType>
+{
+    fn new(ctx: &'l RedactingDumpContext<WriterType>) -> Self {
+        let mut output = Vec::new();
+        if !ctx.header.is_empty() {
+            output.extend_from_slice(&ctx.header);
+            output.push(b'\n');
+        }
+        Self { ctx, output }
+    }
+
+    fn attribute(&mut self, name: &[u8], value: &[u8]) -> Option<IoError> {
+        let sensitive = self
+            .ctx
+            .sensitive_prefixes
+            .iter()
+            .any(|prefix| name.starts_with(prefix));
+        self.output.extend_from_slice(name);
+        self.output.push(b'=');
+        if sensitive && !value.is_empty() {
+            self.output.extend_from_slice(b"***");
+        } else {
+            self.output.extend_from_slice(value);
+        }
+        self.output.push(b'\n');
+        None
+    }
+
+    fn response(mut self) -> Result<PolicyResponse, IoError> {
+        self.output.push(b'\n');
+        // written at once, so the requests of concurrent connections don't interleave
+        let mut output = self.ctx.output.lock().unwrap();
+        output.write_all(&self.output)?;
+        output.flush()?;
+        Ok(PolicyResponse::Dunno)
+    }
+}
+
+/// A handler whose responses can be cached by a [`CachingHandler`].
+///
+/// [`CachingHandler`]: struct.CachingHandler.html
+pub trait CacheableHandler {
+    /// Returns the key identifying the response for the attributes seen so far, e.g. the client address and sender.
+    /// Requests with the same key are assumed to get the same response. Returns `None` if the response for this
+    /// request must not be cached.
+    fn cache_key(&self) -> Option<Vec<u8>>;
+}
+
+/// Context of a [`CachingHandler`], holding the context of the inner handler and the shared cache.
+///
+/// [`CachingHandler`]: struct.CachingHandler.html
+#[derive(Debug)]
+pub struct CachingContext<ContextType, ClockType = SystemClock> {
+    /// The context passed to the inner handler.
+    pub inner: ContextType,
+    /// How long a cached response is used.
+    pub ttl: Duration,
+    /// The clock used to expire cached responses.
+    pub clock: ClockType,
+    cache: Mutex<HashMap<Vec<u8>, (Instant, PolicyResponse)>>,
+}
+
+impl<ContextType> CachingContext<ContextType> {
+    /// Creates a new context with an empty cache keeping responses for `ttl`, passing `inner` to the inner handler.
+    pub fn new(inner: ContextType, ttl: Duration) -> Self {
+        Self::with_clock(inner, ttl, SystemClock)
+    }
+}
+
+impl<ContextType, ClockType: Clock> CachingContext<ContextType, ClockType> {
+    /// Like [`CachingContext::new`], but takes the time from `clock`.
+    ///
+    /// [`CachingContext::new`]: struct.CachingContext.html#method.new
+    pub fn with_clock(inner: ContextType, ttl: Duration, clock: ClockType) -> Self {
+        Self {
+            inner,
+            ttl,
+            clock,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+/// Wraps the handler `HandlerType`, caching its responses in the [`CachingContext`] for expensive policies
+/// (like DNS blocklist lookups) whose result doesn't change for a while.
+///
+/// Once all attributes were passed to the inner handler, it is asked for the [`CacheableHandler::cache_key`]. If a
+/// response for that key was cached less than [`CachingContext::ttl`] ago, it is returned without calling the inner
+/// handler's `response`. Otherwise `response` is called and its result is cached. Errors are not cached.
+/// Expired entries are removed whenever a new response is cached.
+///
+/// [`CachingContext`]: struct.CachingContext.html
+/// [`CacheableHandler::cache_key`]: trait.CacheableHandler.html#tymethod.cache_key
+/// [`CachingContext::ttl`]: struct.CachingContext.html#structfield.ttl
+pub struct CachingHandler<'l, HandlerType, ContextType, ClockType = SystemClock> {
+    inner: HandlerType,
+    ctx: &'l CachingContext<ContextType, ClockType>,
+}
+
+impl<'l, HandlerType, ContextType, ClockType, ErrorType>
+    PolicyRequestHandler<'l, CachingContext<ContextType, ClockType>, ErrorType>
+    for CachingHandler<'l, HandlerType, ContextType, ClockType>
+where
+    HandlerType: PolicyRequestHandler<'l, ContextType, ErrorType> + CacheableHandler,
+    ClockType: Clock,
+{
+    fn new(ctx: &'l CachingContext<ContextType, ClockType>) -> Self {
+        Self {
+            inner: HandlerType::new(&ctx.inner),
+            ctx,
+        }
+    }
+
+    fn attribute(&mut self, name: &[u8], value: &[u8]) -> Option<ErrorType> {
+        self.inner.attribute(name, value)
+    }
+
+    fn raw_line(&mut self, line: &[u8]) {
+        self.inner.raw_line(line)
+    }
+
+    fn response(self) -> Result<PolicyResponse, ErrorType> {
+        let key = match self.inner.cache_key() {
+            Some(key) => key,
+            None => return self.inner.response(),
+        };
+        let ttl = self.ctx.ttl;
+        let now = self.ctx.clock.now();
+        if let Some((cached_at, response)) = self.ctx.cache.lock().unwrap().get(&key) {
+            if now.duration_since(*cached_at) < ttl {
+                return Ok(response.clone());
+            }
+        }
+        // the lock isn't held while the inner handler works, so concurrent misses for one key may compute it twice
+        let response = self.inner.response()?;
+        let mut cache = self.ctx.cache.lock().unwrap();
+        cache.retain(|_, (cached_at, _)| now.duration_since(*cached_at) < ttl);
+        cache.insert(key, (now, response.clone()));
+        Ok(response)
+    }
+}
+
+/// Context of a [`ConcurrencyLimitHandler`], holding the context of the inner handler and the limit shared by
+/// all connections using this context.
+///
+/// [`ConcurrencyLimitHandler`]: struct.ConcurrencyLimitHandler.html
+#[derive(Debug)]
+pub struct ConcurrencyLimitContext<ContextType> {
+    /// The context passed to the inner handler.
+    pub inner: ContextType,
+    /// Sent instead of the inner handler's response if `max_waiting` requests are waiting already.
+    pub fallback: PolicyResponse,
+    max_running: usize,
+    max_waiting: usize,
+    state: Mutex<ConcurrencyState>,
+    finished: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct ConcurrencyState {
+    running: usize,
+    waiting: usize,
+}
+
+impl<ContextType> ConcurrencyLimitContext<ContextType> {
+    /// Creates a new context allowing `max_running` responses to be computed at the same time, with at most
+    /// `max_waiting` more requests queueing for their turn. `max_running` has to be at least 1.
+    pub fn new(inner: ContextType, max_running: usize, max_waiting: usize, fallback: PolicyResponse) -> Self {
+        assert!(max_running > 0, "max_running has to be at least 1");
+        Self {
+            inner,
+            fallback,
+            max_running,
+            max_waiting,
+            state: Mutex::new(ConcurrencyState::default()),
+            finished: Condvar::new(),
+        }
+    }
+
+    /// Waits until fewer than `max_running` responses are being computed and marks one more as running.
+    /// Returns `false` without waiting if the queue is full.
+    fn acquire(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.running < self.max_running {
+            state.running += 1;
+            return true;
+        }
+        if state.waiting >= self.max_waiting {
+            return false;
+        }
+        state.waiting += 1;
+        while state.running >= self.max_running {
+            state = self.finished.wait(state).unwrap();
+        }
+        state.waiting -= 1;
+        state.running += 1;
+        true
+    }
+
+    fn release(&self) {
+        self.state.lock().unwrap().running -= 1;
+        self.finished.notify_one();
+    }
+}
+
+/// Releases the slot taken by `ConcurrencyLimitContext::acquire`, even if the inner handler panics.
+struct RunningGuard<'l, ContextType>(&'l ConcurrencyLimitContext<ContextType>);
+
+impl<'l, ContextType> Drop for RunningGuard<'l, ContextType> {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+/// Wraps the handler `HandlerType`, limiting how many responses of all connections sharing the
+/// [`ConcurrencyLimitContext`] are computed at the same time, e.g. to protect a shared database.
+///
+/// Attributes are passed to the inner handler right away, only its `response` waits for a free slot. If all slots
+/// are taken and the configured number of requests is queueing already, [`ConcurrencyLimitContext::fallback`] is
+/// returned instead of waiting.
+///
+/// [`ConcurrencyLimitContext`]: struct.ConcurrencyLimitContext.html
+/// [`ConcurrencyLimitContext::fallback`]: struct.ConcurrencyLimitContext.html#structfield.fallback
+pub struct ConcurrencyLimitHandler<'l, HandlerType, ContextType> {
+    inner: HandlerType,
+    ctx: &'l ConcurrencyLimitContext<ContextType>,
+}
+
+impl<'l, HandlerType, ContextType, ErrorType> PolicyRequestHandler<'l, ConcurrencyLimitContext<ContextType>, ErrorType>
+    for ConcurrencyLimitHandler<'l, HandlerType, ContextType>
+where
+    HandlerType: PolicyRequestHandler<'l, ContextType, ErrorType>,
+{
+    fn new(ctx: &'l ConcurrencyLimitContext<ContextType>) -> Self {
+        Self {
+            inner: HandlerType::new(&ctx.inner),
+            ctx,
+        }
+    }
+
+    fn attribute(&mut self, name: &[u8], value: &[u8]) -> Option<ErrorType> {
+        self.inner.attribute(name, value)
+    }
+
+    fn raw_line(&mut self, line: &[u8]) {
+        self.inner.raw_line(line)
+    }
+
+    fn response(self) -> Result<PolicyResponse, ErrorType> {
+        if !self.ctx.acquire() {
+            return Ok(self.ctx.fallback.clone());
+        }
+        let _guard = RunningGuard(self.ctx);
+        self.inner.response()
+    }
+}
+
+/// Context of a [`RejectThrottleHandler`], holding the context of the inner handler and the reject counts shared by
+/// all connections using this context.
+///
+/// [`RejectThrottleHandler`]: struct.RejectThrottleHandler.html
+#[derive(Debug)]
+pub struct RejectThrottleContext<ContextType, ClockType = SystemClock> {
+    /// The context passed to the inner handler.
+    pub inner: ContextType,
+    /// How many rejects a client gets within `window` before they are throttled.
+    pub max_rejects: usize,
+    /// The time rejects of a client are counted for, starting with its first reject.
+    pub window: Duration,
+    /// Sent instead of the inner handler's reject once a client is throttled, e.g. `Dunno` to leave the decision to
+    /// the following restrictions, or a `Defer` with a short message.
+    pub throttled_response: PolicyResponse,
+    /// The clock used to time the windows.
+    pub clock: ClockType,
+    rejects: Mutex<HashMap<IpAddr, (Instant, usize)>>,
+}
+
+impl<ContextType> RejectThrottleContext<ContextType> {
+    /// Creates a new context allowing `max_rejects` rejects per client within `window`, answering further rejects
+    /// with `throttled_response`. `inner` is passed to the inner handler.
+    pub fn new(inner: ContextType, max_rejects: usize, window: Duration, throttled_response: PolicyResponse) -> Self {
+        Self::with_clock(inner, max_rejects, window, throttled_response, SystemClock)
+    }
+}
+
+impl<ContextType, ClockType: Clock> RejectThrottleContext<ContextType, ClockType> {
+    /// Like [`RejectThrottleContext::new`], but takes the time from `clock`.
+    ///
+    /// [`RejectThrottleContext::new`]: struct.RejectThrottleContext.html#method.new
+    pub fn with_clock(
+        inner: ContextType,
+        max_rejects: usize,
+        window: Duration,
+        throttled_response: PolicyResponse,
+        clock: ClockType,
+    ) -> Self {
+        Self {
+            inner,
+            max_rejects,
+            window,
+            throttled_response,
+            clock,
+            rejects: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+/// Wraps the handler `HandlerType`, replacing its rejects for a client that was rejected too often recently, so a
+/// scanner hammering the server doesn't flood the mail log with rejections.
+///
+/// Rejects (see [`PolicyResponse::is_reject`]) are counted per `client_address`. Once a client got
+/// [`RejectThrottleContext::max_rejects`] rejects within [`RejectThrottleContext::window`], further rejects are
+/// replaced by [`RejectThrottleContext::throttled_response`] until the window ends. Other responses are passed on
+/// unchanged and don't count, as do requests without or with an unparseable `client_address`. Addresses are parsed
+/// with [`normalize_and_parse_ip`], so all forms of one address share a count. Expired counts are removed whenever a
+/// new client is counted.
+///
+/// [`normalize_and_parse_ip`]: ../fn.normalize_and_parse_ip.html
+/// [`PolicyResponse::is_reject`]: ../enum.PolicyResponse.html#method.is_reject
+/// [`RejectThrottleContext::max_rejects`]: struct.RejectThrottleContext.html#structfield.max_rejects
+/// [`RejectThrottleContext::window`]: struct.RejectThrottleContext.html#structfield.window
+/// [`RejectThrottleContext::throttled_response`]: struct.RejectThrottleContext.html#structfield.throttled_response
+pub struct RejectThrottleHandler<'l, HandlerType, ContextType, ClockType = SystemClock> {
+    inner: HandlerType,
+    ctx: &'l RejectThrottleContext<ContextType, ClockType>,
+    client_address: Option<IpAddr>,
+}
+
+impl<'l, HandlerType, ContextType, ClockType, ErrorType>
+    PolicyRequestHandler<'l, RejectThrottleContext<ContextType, ClockType>, ErrorType>
+    for RejectThrottleHandler<'l, HandlerType, ContextType, ClockType>
+where
+    HandlerType: PolicyRequestHandler<'l, ContextType, ErrorType>,
+    ClockType: Clock,
+{
+    fn new(ctx: &'l RejectThrottleContext<ContextType, ClockType>) -> Self {
+        Self {
+            inner: HandlerType::new(&ctx.inner),
+            ctx,
+            client_address: None,
+        }
+    }
+
+    fn attribute(&mut self, name: &[u8], value: &[u8]) -> Option<ErrorType> {
+        if name == b"client_address" {
+            self.client_address = normalize_and_parse_ip(value);
+        }
+        self.inner.attribute(name, value)
+    }
+
+    fn raw_line(&mut self, line: &[u8]) {
+        self.inner.raw_line(line)
+    }
+
+    fn response(self) -> Result<PolicyResponse, ErrorType> {
+        let response = self.inner.response()?;
+        let address = match self.client_address {
+            Some(address) if response.is_reject() => address,
+            _ => return Ok(response),
+        };
+        let (window, now) = (self.ctx.window, self.ctx.clock.now());
+        let mut rejects = self.ctx.rejects.lock().unwrap();
+        if !rejects.contains_key(&address) {
+            rejects.retain(|_, (first, _)| now.duration_since(*first) < window);
+        }
+        let (first, count) = rejects.entry(address).or_insert((now, 0));
+        if now.duration_since(*first) >= window {
+            *first = now;
+            *count = 0;
+        }
+        *count += 1;
+        if *count > self.ctx.max_rejects {
+            return Ok(self.ctx.throttled_response.clone());
+        }
+        Ok(response)
+    }
+}
+
+/// Collects all attributes into a `HashMap` and decides with the closure given as context. Handy for prototypes
+/// and small scripts where the allocations don't matter.
+///
+/// The context is the closure, called once per request with the map from attribute name to value. If an attribute
+/// is sent more than once, the last value wins.
+///
+/// ## Example
+/// ```norun
+/// let decide = |attributes: &HashMap<Vec<u8>, Vec<u8>>| -> Result<PolicyResponse, ()> {
+///     match attributes.get(&b"client_address"[..]) {
+///         Some(address) if address == b"192.0.2.1" => Ok(PolicyResponse::Reject(Vec::new())),
+///         _ => Ok(PolicyResponse::Dunno),
+///     }
+/// };
+/// handle_connection::<MapHandler<_>, _, _, _>(&conn, &decide)?;
+/// ```
+pub struct MapHandler<'l, DecideType> {
+    decide: &'l DecideType,
+    attributes: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl<'l, DecideType, ErrorType> PolicyRequestHandler<'l, DecideType, ErrorType> for MapHandler<'l, DecideType>
+where
+    DecideType: Fn(&HashMap<Vec<u8>, Vec<u8>>) -> Result<PolicyResponse, ErrorType>,
+{
+    fn new(decide: &'l DecideType) -> Self {
+        Self {
+            decide,
+            attributes: HashMap::new(),
+        }
+    }
+
+    fn attribute(&mut self, name: &[u8], value: &[u8]) -> Option<ErrorType> {
+        self.attributes.insert(name.to_vec(), value.to_vec());
+        None
+    }
+
+    fn response(self) -> Result<PolicyResponse, ErrorType> {
+        (self.decide)(&self.attributes)
+    }
+}
+
+/// A network in CIDR notation like `192.0.2.0/24` or `2001:db8::/32`, see [`CidrListHandler`].
+///
+/// [`CidrListHandler`]: struct.CidrListHandler.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Parses a network given as `address/prefix_len`. An address without `/prefix_len` matches only itself.
+    /// Returns `None` if the address is invalid or the prefix length too large for it.
+    pub fn parse(cidr: &str) -> Option<Cidr> {
+        let (address, prefix_len) = match cidr.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len.parse().ok()?)),
+            None => (cidr, None),
+        };
+        let network: IpAddr = address.parse().ok()?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = prefix_len.unwrap_or(max_len);
+        if prefix_len > max_len {
+            return None;
+        }
+        Some(Cidr { network, prefix_len })
+    }
+
+    /// Returns `true` if `address` is part of this network. IPv4 addresses never match IPv6 networks and vice versa.
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        match (self.network, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_len)).unwrap_or(0);
+                u32::from(network) & mask == u32::from(*address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix_len)).unwrap_or(0);
+                u128::from(network) & mask == u128::from(*address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Context of a [`CidrListHandler`], holding the networks to allow and deny.
+///
+/// [`CidrListHandler`]: struct.CidrListHandler.html
+#[derive(Debug, Clone)]
+pub struct CidrListContext {
+    /// Clients from these networks are answered with `OK`.
+    pub allow: Vec<Cidr>,
+    /// Clients from these networks are answered with `deny_response`, unless they are in `allow` as well.
+    pub deny: Vec<Cidr>,
+    /// The response for denied clients, usually a `Reject`.
+    pub deny_response: PolicyResponse,
+}
+
+/// Allows or denies clients by their `client_address`, using the networks in the [`CidrListContext`].
+///
+/// Clients in an `allow` network get `OK`, clients in a `deny` network get the `deny_response`. `allow` is checked
+/// first, so it can be used for exceptions from larger denied networks. Everything else, including requests without
+/// or with an unparseable `client_address`, gets `DUNNO` so the following restrictions decide.
+/// Addresses are parsed with [`normalize_and_parse_ip`], so Postfix' `ipv6:` prefix is accepted.
+///
+/// [`CidrListContext`]: struct.CidrListContext.html
+/// [`normalize_and_parse_ip`]: ../fn.normalize_and_parse_ip.html
+pub struct CidrListHandler<'l> {
+    ctx: &'l CidrListContext,
+    client_address: Option<IpAddr>,
+}
+
+impl<'l, ErrorType> PolicyRequestHandler<'l, CidrListContext, ErrorType> for CidrListHandler<'l> {
+    fn new(ctx: &'l CidrListContext) -> Self {
+        Self {
+            ctx,
+            client_address: None,
+        }
+    }
+
+    fn attribute(&mut self, name: &[u8], value: &[u8]) -> Option<ErrorType> {
+        if name == b"client_address" {
+            self.client_address = normalize_and_parse_ip(value);
+        }
+        None
+    }
+
+    fn response(self) -> Result<PolicyResponse, ErrorType> {
+        let address = match self.client_address {
+            Some(address) => address,
+            None => return Ok(PolicyResponse::Dunno),
+        };
+        if self.ctx.allow.iter().any(|cidr| cidr.contains(&address)) {
+            return Ok(PolicyResponse::Ok);
+        }
+        if self.ctx.deny.iter().any(|cidr| cidr.contains(&address)) {
+            return Ok(self.ctx.deny_response.clone());
+        }
+        Ok(PolicyResponse::Dunno)
+    }
+}
+
+/// Rejects messages larger than the maximum size in bytes given as context, like Postfix' `message_size_limit`
+/// but e.g. per client or sender when combined with other handlers.
+///
+/// Only requests in the `END-OF-MESSAGE` stage are checked, as only then `size` is the actual message size (in
+/// earlier stages it is the size the client announced, if any). Oversized messages get
+/// `552 5.3.4 Message size exceeds fixed limit`, the reply Postfix uses itself. Everything else, including requests
+/// without or with an unparseable `size`, gets `DUNNO`.
+///
+/// ## Example
+/// ```norun
+/// let max_size: u64 = 10 * 1024 * 1024;
+/// handle_connection::<MaxSizeHandler, _, _, _>(&conn, &max_size)?;
+/// ```
+pub struct MaxSizeHandler<'l> {
+    max_size: &'l u64,
+    size: Option<u64>,
+    end_of_message: bool,
+}
+
+impl<'l, ErrorType> PolicyRequestHandler<'l, u64, ErrorType> for MaxSizeHandler<'l> {
+    fn new(max_size: &'l u64) -> Self {
+        Self {
+            max_size,
+            size: None,
+            end_of_message: false,
+        }
+    }
+
+    fn attribute(&mut self, name: &[u8], value: &[u8]) -> Option<ErrorType> {
+        match name {
+            b"size" => self.size = parse_u64(value),
+            b"protocol_state" => self.end_of_message = ProtocolState::parse(value).is_end_of_message(),
+            _ => {}
+        }
+        None
+    }
+
+    fn response(self) -> Result<PolicyResponse, ErrorType> {
+        match self.size {
+            Some(size) if self.end_of_message && size > *self.max_size => Ok(PolicyResponse::RejectCoded {
+                code: Some(552),
+                text: b"5.3.4 Message size exceeds fixed limit".to_vec(),
+            }),
+            _ => Ok(PolicyResponse::Dunno),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        CacheableHandler, CachingContext, CachingHandler, ChainContext, ChainHandler, Cidr, CidrListContext,
+        CidrListHandler, ConcurrencyLimitContext, ConcurrencyLimitHandler, MapHandler, MaxSizeHandler, RecordedRequest,
+        RecordingContext, RecordingHandler, RedactingDumpContext, RedactingDumpHandler, RejectThrottleContext,
+        RejectThrottleHandler, TimeoutContext, TimeoutHandler,
+    };
+    use crate::test_helper::{assert_response, handle_connection_response, FakeClock};
+    use crate::{ParsedRequest, PolicyRequestHandler, PolicyResponse};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    struct SenderHandler<'l> {
+        blocked: &'l [u8],
+        sender: Vec<u8>,
+    }
+    impl<'l> PolicyRequestHandler<'l, Vec<u8>, ()> for SenderHandler<'l> {
+        fn new(blocked: &'l Vec<u8>) -> Self {
+            Self {
+                blocked,
+                sender: Vec::new(),
+            }
+        }
+        fn attribute(&mut self, name: &[u8], value: &[u8]) -> Option<()> {
+            if name == b"sender" {
+                self.sender = value.to_vec();
+            }
+            None
+        }
+        fn response(self) -> Result<PolicyResponse, ()> {
+            if self.sender == self.blocked {
+                return Ok(PolicyResponse::Reject(b"blocked".to_vec()));
+            }
+            Ok(PolicyResponse::Dunno)
+        }
+    }
+
+    fn request(attributes: &[(&[u8], &[u8])]) -> ParsedRequest {
+        let mut request = ParsedRequest::new();
+        for (name, value) in attributes {
+            request.push(name, value);
+        }
+        request
+    }
+
+    #[test]
+    fn test_recording_handler() {
+        let ctx = RecordingContext::new(b"spam@example.com".to_vec());
+        let input = b"request=smtpd_access_policy\nsender=spam@example.com\n\n\
+        request=smtpd_access_policy\nsender=friend@example.com\n\n";
+        let output = handle_connection_response::<RecordingHandler<SenderHandler, _>, _, _>(input, &ctx).unwrap();
+        assert_eq!(output, b"action=REJECT blocked\n\naction=DUNNO\n\n");
+        assert_eq!(
+            ctx.transcript(),
+            vec![
+                RecordedRequest {
+                    request: request(&[(b"request", b"smtpd_access_policy"), (b"sender", b"spam@example.com")]),
+                    response: PolicyResponse::Reject(b"blocked".to_vec()),
+                },
+                RecordedRequest {
+                    request: request(&[(b"request", b"smtpd_access_policy"), (b"sender", b"friend@example.com")]),
+                    response: PolicyResponse::Dunno,
+                },
+            ]
+        );
+    }
+
+    struct SleepingHandler {
+        sleep: Duration,
+    }
+    impl<'l> PolicyRequestHandler<'l, Duration, ()> for SleepingHandler {
+        fn new(sleep: &Duration) -> Self {
+            Self { sleep: *sleep }
+        }
+        fn attribute(&mut self, _: &[u8], _: &[u8]) -> Option<()> {
+            None
+        }
+        fn response(self) -> Result<PolicyResponse, ()> {
+            std::thread::sleep(self.sleep);
+            Ok(PolicyResponse::Ok)
+        }
+    }
+
+    #[test]
+    fn test_timeout_handler() {
+        let input = b"request=smtpd_access_policy\n\n";
+        let ctx = TimeoutContext {
+            inner: Duration::from_millis(500),
+            deadline: Duration::from_millis(20),
+            fallback: PolicyResponse::Defer(b"4.3.0 try again later".to_vec()),
+        };
+        assert_response::<TimeoutHandler<SleepingHandler, _>, _, _>(
+            input,
+            &ctx,
+            PolicyResponse::Defer(b"4.3.0 try again later".to_vec()),
+        );
+
+        let ctx = TimeoutContext {
+            inner: Duration::from_millis(0),
+            deadline: Duration::from_secs(10),
+            fallback: PolicyResponse::Dunno,
+        };
+        assert_response::<TimeoutHandler<SleepingHandler, _>, _, _>(input, &ctx, PolicyResponse::Ok);
+    }
+
+    #[test]
+    fn test_redacting_dump_handler() {
+        let ctx = RedactingDumpContext::new(Vec::new());
+        let input = b"request=smtpd_access_policy\nclient_address=192.0.2.1\nsasl_username=\n\
+        sasl_password=hunter2\nccert_subject=mx.example.com\n\n";
+        let output = handle_connection_response::<RedactingDumpHandler<_>, _, _>(input, &ctx).unwrap();
+        assert_eq!(output, b"action=DUNNO\n\n");
+        assert_eq!(
+            String::from_utf8(ctx.into_output()).unwrap(),
+            "request=smtpd_access_policy\nclient_address=192.0.2.1\nsasl_username=\n\
+            sasl_password=***\nccert_subject=***\n\n"
+        );
+
+        let ctx = RedactingDumpContext::new(Vec::new()).with_header("Request on Connection #3");
+        handle_connection_response::<RedactingDumpHandler<_>, _, _>(b"sasl_username=user\n\nsender=\n\n", &ctx)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(ctx.into_output()).unwrap(),
+            "Request on Connection #3\nsasl_username=***\n\nRequest on Connection #3\nsender=\n\n"
+        );
+    }
+
+    /// Answers with the number of times `response` was called so far, so cached responses are recognizable.
+    struct CountingSenderHandler<'l> {
+        calls: &'l AtomicUsize,
+        sender: Vec<u8>,
+    }
+    impl<'l> PolicyRequestHandler<'l, AtomicUsize, ()> for CountingSenderHandler<'l> {
+        fn new(calls: &'l AtomicUsize) -> Self {
+            Self {
+                calls,
+                sender: Vec::new(),
+            }
+        }
+        fn attribute(&mut self, name: &[u8], value: &[u8]) -> Option<()> {
+            if name == b"sender" {
+                self.sender = value.to_vec();
+            }
+            None
+        }
+        fn response(self) -> Result<PolicyResponse, ()> {
+            let calls = self.calls.fetch_add(1, Ordering::SeqCst) + 1;
+            Ok(PolicyResponse::Info(calls.to_string().into_bytes()))
+        }
+    }
+    impl<'l> CacheableHandler for CountingSenderHandler<'l> {
+        fn cache_key(&self) -> Option<Vec<u8>> {
+            Some(self.sender.clone()).filter(|sender| !sender.is_empty())
+        }
+    }
+
+    #[test]
+    fn test_caching_handler() {
+        let ctx = CachingContext::with_clock(AtomicUsize::new(0), Duration::from_secs(60), FakeClock::new());
+        let request = b"request=smtpd_access_policy\nsender=a@example.com\n\n";
+        let info = |text: &[u8]| PolicyResponse::Info(text.to_vec());
+        type Handler<'l> = CachingHandler<'l, CountingSenderHandler<'l>, AtomicUsize, FakeClock>;
+
+        assert_response::<Handler, _, _>(request, &ctx, info(b"1"));
+        ctx.clock.advance(Duration::from_secs(59));
+        assert_response::<Handler, _, _>(request, &ctx, info(b"1"));
+        assert_response::<Handler, _, _>(b"sender=b@example.com\n\n", &ctx, info(b"2"));
+        assert_response::<Handler, _, _>(b"sender=\n\n", &ctx, info(b"3"));
+        assert_response::<Handler, _, _>(b"sender=\n\n", &ctx, info(b"4"));
+
+        ctx.clock.advance(Duration::from_secs(1));
+        assert_response::<Handler, _, _>(request, &ctx, info(b"5"));
+        assert_response::<Handler, _, _>(request, &ctx, info(b"5"));
+    }
+
+    #[derive(Default)]
+    struct Running {
+        current: AtomicUsize,
+        max: AtomicUsize,
+        blocked: AtomicBool,
+    }
+
+    /// Records how many responses run at the same time. Each response takes at least 20ms and waits while `blocked`.
+    struct RunningHandler<'l> {
+        running: &'l Running,
+    }
+    impl<'l> PolicyRequestHandler<'l, Running, ()> for RunningHandler<'l> {
+        fn new(running: &'l Running) -> Self {
+            Self { running }
+        }
+        fn attribute(&mut self, _: &[u8], _: &[u8]) -> Option<()> {
+            None
+        }
+        fn response(self) -> Result<PolicyResponse, ()> {
+            let current = self.running.current.fetch_add(1, Ordering::SeqCst) + 1;
+            self.running.max.fetch_max(current, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(20));
+            while self.running.blocked.load(Ordering::SeqCst) {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            self.running.current.fetch_sub(1, Ordering::SeqCst);
+            Ok(PolicyResponse::Dunno)
+        }
+    }
+
+    #[test]
+    fn test_concurrency_limit_handler() {
+        let ctx = ConcurrencyLimitContext::new(Running::default(), 1, 10, PolicyResponse::Defer(Vec::new()));
+        let input = b"request=smtpd_access_policy\n\n";
+        std::thread::scope(|scope| {
+            for _ in 0..2 {
+                scope.spawn(|| {
+                    assert_response::<ConcurrencyLimitHandler<RunningHandler, _>, _, _>(
+                        input,
+                        &ctx,
+                        PolicyResponse::Dunno,
+                    )
+                });
+            }
+        });
+        assert_eq!(ctx.inner.max.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_concurrency_limit_handler_fallback() {
+        let ctx = ConcurrencyLimitContext::new(Running::default(), 1, 0, PolicyResponse::Defer(b"busy".to_vec()));
+        ctx.inner.blocked.store(true, Ordering::SeqCst);
+        let input = b"request=smtpd_access_policy\n\n";
+        type Handler<'l> = ConcurrencyLimitHandler<'l, RunningHandler<'l>, Running>;
+        std::thread::scope(|scope| {
+            scope.spawn(|| assert_response::<Handler, _, _>(input, &ctx, PolicyResponse::Dunno));
+            while ctx.inner.current.load(Ordering::SeqCst) == 0 {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            assert_response::<Handler, _, _>(input, &ctx, PolicyResponse::Defer(b"busy".to_vec()));
+            ctx.inner.blocked.store(false, Ordering::SeqCst);
+        });
+        assert_response::<Handler, _, _>(input, &ctx, PolicyResponse::Dunno);
+    }
+
+    #[test]
+    fn test_reject_throttle_handler() {
+        let ctx = RejectThrottleContext::with_clock(
+            b"spam@example.com".to_vec(),
+            2,
+            Duration::from_secs(60),
+            PolicyResponse::Dunno,
+            FakeClock::new(),
+        );
+        type Handler<'l> = RejectThrottleHandler<'l, SenderHandler<'l>, Vec<u8>, FakeClock>;
+        let spam = |client: &str| {
+            format!(
+                "request=smtpd_access_policy\nclient_address={}\nsender=spam@example.com\n\n",
+                client
+            )
+            .into_bytes()
+        };
+        let rejected = PolicyResponse::Reject(b"blocked".to_vec());
+
+        assert_response::<Handler, _, _>(&spam("192.0.2.1"), &ctx, rejected.clone());
+        assert_response::<Handler, _, _>(
+            b"client_address=192.0.2.1\nsender=a@b.c\n\n",
+            &ctx,
+            PolicyResponse::Dunno,
+        );
+        assert_response::<Handler, _, _>(&spam("192.0.2.1"), &ctx, rejected.clone());
+        // the third reject within the window is throttled, other clients aren't affected
+        assert_response::<Handler, _, _>(&spam("192.0.2.1"), &ctx, PolicyResponse::Dunno);
+        assert_response::<Handler, _, _>(&spam("192.0.2.1"), &ctx, PolicyResponse::Dunno);
+        assert_response::<Handler, _, _>(&spam("192.0.2.2"), &ctx, rejected.clone());
+        // without a client_address or with an unparseable one nothing is counted
+        for _ in 0..3 {
+            assert_response::<Handler, _, _>(b"sender=spam@example.com\n\n", &ctx, rejected.clone());
+            assert_response::<Handler, _, _>(&spam("unknown"), &ctx, rejected.clone());
+        }
+        // all forms of an address share one count
+        assert_response::<Handler, _, _>(&spam("ipv6:2001:db8::1"), &ctx, rejected.clone());
+        assert_response::<Handler, _, _>(&spam("2001:db8::1"), &ctx, rejected.clone());
+        assert_response::<Handler, _, _>(&spam("[2001:db8::1]"), &ctx, PolicyResponse::Dunno);
+
+        ctx.clock.advance(Duration::from_secs(60));
+        assert_response::<Handler, _, _>(&spam("192.0.2.1"), &ctx, rejected.clone());
+        assert_response::<Handler, _, _>(&spam("192.0.2.1"), &ctx, rejected);
+        assert_response::<Handler, _, _>(&spam("192.0.2.1"), &ctx, PolicyResponse::Dunno);
+    }
+
+    #[test]
+    fn test_map_handler() {
+        let decide = |attributes: &HashMap<Vec<u8>, Vec<u8>>| -> Result<PolicyResponse, ()> {
+            match (
+                attributes.get(&b"protocol_state"[..]),
+                attributes.get(&b"client_address"[..]),
+            ) {
+                (Some(state), Some(address)) if state == b"RCPT" => Ok(PolicyResponse::Defer(address.clone())),
+                _ => Ok(PolicyResponse::Dunno),
+            }
+        };
+        let input = b"request=smtpd_access_policy\nprotocol_state=RCPT\nprotocol_name=ESMTP\n\
+        client_address=10.0.0.1\nclient_address=131.234.189.14\n\n";
+        assert_response::<MapHandler<_>, _, _>(input, &decide, PolicyResponse::Defer(b"131.234.189.14".to_vec()));
+        assert_response::<MapHandler<_>, _, _>(b"protocol_state=DATA\n\n", &decide, PolicyResponse::Dunno);
+    }
+
+    #[test]
+    fn test_cidr() {
+        let network = Cidr::parse("192.0.2.0/24").unwrap();
+        assert!(network.contains(&"192.0.2.200".parse().unwrap()));
+        assert!(!network.contains(&"192.0.3.1".parse().unwrap()));
+        assert!(!network.contains(&"::ffff:192.0.2.1".parse().unwrap()));
+        assert!(Cidr::parse("0.0.0.0/0")
+            .unwrap()
+            .contains(&"203.0.113.9".parse().unwrap()));
+        assert!(Cidr::parse("2001:db8::/32")
+            .unwrap()
+            .contains(&"2001:db8:1::1".parse().unwrap()));
+        assert!(Cidr::parse("2001:db8::1")
+            .unwrap()
+            .contains(&"2001:db8::1".parse().unwrap()));
+        assert!(!Cidr::parse("2001:db8::1")
+            .unwrap()
+            .contains(&"2001:db8::2".parse().unwrap()));
+        assert_eq!(Cidr::parse("192.0.2.0/33"), None);
+        assert_eq!(Cidr::parse("192.0.2.0/"), None);
+        assert_eq!(Cidr::parse("example.com"), None);
+    }
+
+    #[test]
+    fn test_cidr_list_handler() {
+        let ctx = CidrListContext {
+            allow: vec![
+                Cidr::parse("192.0.2.10").unwrap(),
+                Cidr::parse("2001:db8:1::/48").unwrap(),
+            ],
+            deny: vec![
+                Cidr::parse("192.0.2.0/24").unwrap(),
+                Cidr::parse("2001:db8::/32").unwrap(),
+            ],
+            deny_response: PolicyResponse::Reject(b"5.7.1 Access denied".to_vec()),
+        };
+        let denied = PolicyResponse::Reject(b"5.7.1 Access denied".to_vec());
+        assert_response::<CidrListHandler, _, ()>(b"client_address=192.0.2.10\n\n", &ctx, PolicyResponse::Ok);
+        assert_response::<CidrListHandler, _, ()>(b"client_address=192.0.2.11\n\n", &ctx, denied.clone());
+        assert_response::<CidrListHandler, _, ()>(b"client_address=198.51.100.1\n\n", &ctx, PolicyResponse::Dunno);
+        assert_response::<CidrListHandler, _, ()>(b"client_address=ipv6:2001:db8:1::5\n\n", &ctx, PolicyResponse::Ok);
+        assert_response::<CidrListHandler, _, ()>(b"client_address=[2001:db8:2::5]\n\n", &ctx, denied);
+        assert_response::<CidrListHandler, _, ()>(b"client_address=unknown\n\n", &ctx, PolicyResponse::Dunno);
+    }
+
+    #[test]
+    fn test_chain_handler() {
+        let ctx = ChainContext {
+            first: CidrListContext {
+                allow: vec![Cidr::parse("192.0.2.0/24").unwrap()],
+                deny: Vec::new(),
+                deny_response: PolicyResponse::Reject(Vec::new()),
+            },
+            second: b"spam@example.com".to_vec(),
+        };
+        let blocked = PolicyResponse::Reject(b"blocked".to_vec());
+        // OK from the allow list is terminal, the sender isn't checked anymore
+        assert_response::<ChainHandler<CidrListHandler, SenderHandler>, _, ()>(
+            b"client_address=192.0.2.1\nsender=spam@example.com\n\n",
+            &ctx,
+            PolicyResponse::Ok,
+        );
+        assert_response::<ChainHandler<CidrListHandler, SenderHandler>, _, ()>(
+            b"client_address=198.51.100.1\nsender=spam@example.com\n\n",
+            &ctx,
+            blocked,
+        );
+        assert_response::<ChainHandler<CidrListHandler, SenderHandler>, _, ()>(
+            b"client_address=198.51.100.1\nsender=ham@example.com\n\n",
+            &ctx,
+            PolicyResponse::Dunno,
+        );
+    }
+
+    #[test]
+    fn test_max_size_handler() {
+        let max_size = 1000u64;
+        // parsed back from the wire, so the code is part of the message
+        let too_large = PolicyResponse::Reject(b"552 5.3.4 Message size exceeds fixed limit".to_vec());
+        assert_response::<MaxSizeHandler, _, ()>(
+            b"request=smtpd_access_policy\nprotocol_state=END-OF-MESSAGE\nsize=1001\n\n",
+            &max_size,
+            too_large,
+        );
+        assert_response::<MaxSizeHandler, _, ()>(
+            b"request=smtpd_access_policy\nprotocol_state=END-OF-MESSAGE\nsize=1000\n\n",
+            &max_size,
+            PolicyResponse::Dunno,
+        );
+        // earlier stages only know the announced size, or none at all
+        assert_response::<MaxSizeHandler, _, ()>(
+            b"request=smtpd_access_policy\nprotocol_state=RCPT\nsize=5000\n\n",
+            &max_size,
+            PolicyResponse::Dunno,
+        );
+        assert_response::<MaxSizeHandler, _, ()>(
+            b"request=smtpd_access_policy\nprotocol_state=RCPT\nsize=\n\n",
+            &max_size,
+            PolicyResponse::Dunno,
+        );
+        assert_response::<MaxSizeHandler, _, ()>(
+            b"request=smtpd_access_policy\nprotocol_state=END-OF-MESSAGE\n\n",
+            &max_size,
+            PolicyResponse::Dunno,
+        );
+    }
+}
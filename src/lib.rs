@@ -2,7 +2,25 @@
 A Postfix SMTP access policy delegation handler. It handles protocol parsing and response sending to talk to Postfix
  */
 
-use std::io::{BufRead, BufReader, Read, Write};
+use std::io::{BufRead, BufReader, ErrorKind, IoSlice, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub mod handlers;
+mod metrics;
+mod output;
+mod request;
+#[cfg(unix)]
+mod serve;
+mod session;
+
+pub use metrics::{Clock, Metrics, NoMetrics, RequestTimings, SystemClock};
+pub use output::{handle_connection_with_output, OutputPolicyRequestHandler};
+pub use request::{normalize_and_parse_ip, parse_u64, CorrelationId, ParsedRequest, ProtocolState, SaslMethod};
+#[cfg(unix)]
+pub use serve::{serve_unix, ConnectionInfo};
+pub use session::{handle_connection_sessioned, SessionPolicyRequestHandler};
 
 /// Errors that can occur in this Crate
 #[derive(Debug)]
@@ -15,6 +33,55 @@ pub enum PostfixPolicyError<ErrorType> {
     ///
     /// [`PolicyRequestHandler`]: trait.PolicyRequestHandler.html
     HandlerError(ErrorType),
+    /// The request announced a protocol version that isn't in [`Limits::accepted_protocol_versions`].
+    ///
+    /// [`Limits::accepted_protocol_versions`]: struct.Limits.html#structfield.accepted_protocol_versions
+    UnsupportedProtocolVersion(Vec<u8>),
+    /// The response returned by the [`PolicyRequestHandler`] can't be sent to the server.
+    ///
+    /// [`PolicyRequestHandler`]: trait.PolicyRequestHandler.html
+    InvalidResponse(ResponseError),
+    /// The value of attribute `name` is not valid UTF-8, but `name` is listed in [`Limits::utf8_attributes`].
+    ///
+    /// [`Limits::utf8_attributes`]: struct.Limits.html#structfield.utf8_attributes
+    InvalidUtf8 { name: Vec<u8> },
+    /// Reading from the socket failed with `std::io::ErrorKind::WouldBlock`. Either the socket is in non-blocking
+    /// mode, which isn't supported (the socket has to be blocking), or the read timeout set on the socket elapsed.
+    WouldBlock,
+    /// The input ended in the middle of this line and [`Limits::require_terminated_lines`] is set.
+    ///
+    /// [`Limits::require_terminated_lines`]: struct.Limits.html#structfield.require_terminated_lines
+    UnterminatedLine(Vec<u8>),
+    /// One of the [`Limits`] was exceeded, `limit` tells which one.
+    ///
+    /// [`Limits`]: struct.Limits.html
+    LimitExceeded { limit: LimitKind },
+    /// An attribute name contained bytes other than `a-z` and `_` while [`Limits::strict_attribute_names`] is set.
+    ///
+    /// [`Limits::strict_attribute_names`]: struct.Limits.html#structfield.strict_attribute_names
+    InvalidAttributeName(Vec<u8>),
+}
+
+/// The limit reported by [`PostfixPolicyError::LimitExceeded`], named after the field of [`Limits`] configuring it.
+///
+/// [`PostfixPolicyError::LimitExceeded`]: enum.PostfixPolicyError.html#variant.LimitExceeded
+/// [`Limits`]: struct.Limits.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitKind {
+    /// The connection was open for longer than `Limits::max_connection_lifetime`.
+    MaxConnectionLifetime,
+    /// An attribute name was longer than `Limits::max_name_len`.
+    MaxNameLen,
+    /// A request line was longer than `Limits::max_line_len`.
+    MaxLineLen,
+    /// A request contained more attributes than `Limits::max_attributes`.
+    MaxAttributes,
+    /// A request wasn't received completely within `Limits::max_request_duration`.
+    MaxRequestDuration,
+    /// The peer paused for longer than `Limits::read_timeout` within a request.
+    ReadTimeout,
+    /// A request contained more lines skipped by the lenient settings than `Limits::max_skipped_lines`.
+    MaxSkippedLines,
 }
 
 impl<ErrorType> std::convert::From<std::io::Error> for PostfixPolicyError<ErrorType> {
@@ -23,23 +90,318 @@ impl<ErrorType> std::convert::From<std::io::Error> for PostfixPolicyError<ErrorT
     }
 }
 
+/// Errors that can occur while building a [`PolicyResponse`].
+///
+/// [`PolicyResponse`]: enum.PolicyResponse.html
+#[derive(Debug, PartialEq)]
+pub enum ResponseError {
+    /// The SMTP reply code is out of range for the kind of response.
+    InvalidReplyCode(u16),
+    /// The enhanced status code is not of the form `class.subject.detail`, or its class doesn't match the reply code.
+    InvalidEnhancedStatus(Vec<u8>),
+    /// The message contains a line feed. As each response is a single line terminated by an empty line, this would
+    /// end the response early and make the server read the rest as another record.
+    MessageContainsNewline(Vec<u8>),
+    /// The target of a `BCC` or `REDIRECT` doesn't look like a bare email address: it needs to contain exactly one `@`
+    /// with a non-empty local part and domain, and no whitespace or angle brackets.
+    InvalidAddress(Vec<u8>),
+    /// The action keyword isn't one of the access(5) actions, or is `OK` or `DUNNO` with a message.
+    UnknownAction(Vec<u8>),
+}
+
 /// Encodes a response to the mail server.
 ///
 /// For details see [`man 5 access`](http://www.postfix.org/access.5.html)
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum PolicyResponse {
+    /// Accepts the request. Postfix ignores any text after `OK`, it is neither sent to the client nor logged,
+    /// so this variant carries none. To leave a note in the mail log, use `Info` or `Warn` instead, which don't
+    /// accept the request, or log it from the handler.
     Ok,
     Reject(Vec<u8>),
     Defer(Vec<u8>),
     DeferIfReject(Vec<u8>),
     DeferIfPermit(Vec<u8>),
+    /// Sends a copy of the message to this address. Like for `Redirect`, this has to be a bare address.
     Bcc(Vec<u8>),
     Discard(Vec<u8>),
     Dunno,
     Hold(Vec<u8>),
+    /// Delivers the message to this address instead of its recipients. Postfix expects a bare address like
+    /// `user@example.com`; forms with a display name or angle brackets like `"Name" <user@example.com>` are not
+    /// understood and fail serialization with `ResponseError::InvalidAddress`. See [`PolicyResponse::redirect`].
+    ///
+    /// [`PolicyResponse::redirect`]: enum.PolicyResponse.html#method.redirect
     Redirect(Vec<u8>),
     Info(Vec<u8>),
     Warn(Vec<u8>),
+    /// A `REJECT` with the SMTP reply `code` kept separately from the `text`. If `code` is set it must be
+    /// a permanent error (5xx) and is sent in front of the text, replacing Postfix' default reply code.
+    RejectCoded {
+        code: Option<u16>,
+        text: Vec<u8>,
+    },
+    /// A `DEFER` with the SMTP reply `code` kept separately from the `text`. If `code` is set it must be
+    /// a temporary error (4xx) and is sent in front of the text, replacing Postfix' default reply code.
+    DeferCoded {
+        code: Option<u16>,
+        text: Vec<u8>,
+    },
+    /// Rejects the request with `421 4.7.0` and the given message. Postfix then sends this reply to the client and
+    /// closes the SMTP session, e.g. to get rid of an abusive client. The connection to the policy server is
+    /// not affected by this.
+    Disconnect(Vec<u8>),
+}
+
+impl PolicyResponse {
+    /// Returns `true` if this response accepts the request and ends restriction evaluation,
+    /// i.e. `OK` or `DISCARD` (which claims successful delivery).
+    pub fn is_accept(&self) -> bool {
+        matches!(self, PolicyResponse::Ok | PolicyResponse::Discard(_))
+    }
+
+    /// Returns `true` if this response rejects the request, either permanently (`REJECT`, `RejectCoded`)
+    /// or temporarily (`DEFER`, `DeferCoded`, `Disconnect`).
+    pub fn is_reject(&self) -> bool {
+        matches!(
+            self,
+            PolicyResponse::Reject(_)
+                | PolicyResponse::Defer(_)
+                | PolicyResponse::RejectCoded { .. }
+                | PolicyResponse::DeferCoded { .. }
+                | PolicyResponse::Disconnect(_)
+        )
+    }
+
+    /// Returns `true` if this response neither accepts nor rejects and has no effect on delivery,
+    /// i.e. `DUNNO` or one of the logging actions `INFO` and `WARN`.
+    ///
+    /// Note that the remaining variants (`DEFER_IF_REJECT`, `DEFER_IF_PERMIT`, `BCC`, `HOLD` and `REDIRECT`)
+    /// are none of accept, reject or pass-through: they modify how the message is handled, but Postfix
+    /// continues evaluating its restrictions afterwards.
+    pub fn is_passthrough(&self) -> bool {
+        matches!(
+            self,
+            PolicyResponse::Dunno | PolicyResponse::Info(_) | PolicyResponse::Warn(_)
+        )
+    }
+
+    /// Returns `true` if Postfix stops evaluating the restriction list after this response, i.e. it accepts or
+    /// rejects (see [`is_accept`] and [`is_reject`]).
+    ///
+    /// `HOLD`, `REDIRECT`, `BCC` and `DEFER_IF_*` are not terminal even though they affect the message: Postfix
+    /// notes them and continues with the next restriction, like for `DUNNO`, `INFO` and `WARN`.
+    ///
+    /// [`is_accept`]: #method.is_accept
+    /// [`is_reject`]: #method.is_reject
+    pub fn is_terminal(&self) -> bool {
+        self.is_accept() || self.is_reject()
+    }
+
+    /// Builds an `Ok`. There is deliberately no variant taking a message, see [`PolicyResponse::Ok`].
+    ///
+    /// [`PolicyResponse::Ok`]: enum.PolicyResponse.html#variant.Ok
+    pub fn ok() -> PolicyResponse {
+        PolicyResponse::Ok
+    }
+
+    // Constructors for handlers working with `String` messages. The byte based variants stay available for
+    // messages that aren't valid UTF-8.
+
+    /// Builds a `Reject` with the given `message`.
+    pub fn reject_str(message: impl Into<String>) -> PolicyResponse {
+        PolicyResponse::Reject(message.into().into_bytes())
+    }
+
+    /// Builds a `Defer` with the given `message`.
+    pub fn defer_str(message: impl Into<String>) -> PolicyResponse {
+        PolicyResponse::Defer(message.into().into_bytes())
+    }
+
+    /// Builds a `DeferIfReject` with the given `message`.
+    pub fn defer_if_reject_str(message: impl Into<String>) -> PolicyResponse {
+        PolicyResponse::DeferIfReject(message.into().into_bytes())
+    }
+
+    /// Builds a `DeferIfPermit` with the given `message`.
+    pub fn defer_if_permit_str(message: impl Into<String>) -> PolicyResponse {
+        PolicyResponse::DeferIfPermit(message.into().into_bytes())
+    }
+
+    /// Builds a `Discard` with the given `message`.
+    pub fn discard_str(message: impl Into<String>) -> PolicyResponse {
+        PolicyResponse::Discard(message.into().into_bytes())
+    }
+
+    /// Builds a `Hold` with the given `message`.
+    pub fn hold_str(message: impl Into<String>) -> PolicyResponse {
+        PolicyResponse::Hold(message.into().into_bytes())
+    }
+
+    /// Builds a `Info` with the given `message`.
+    pub fn info_str(message: impl Into<String>) -> PolicyResponse {
+        PolicyResponse::Info(message.into().into_bytes())
+    }
+
+    /// Builds a `Warn` with the given `message`.
+    pub fn warn_str(message: impl Into<String>) -> PolicyResponse {
+        PolicyResponse::Warn(message.into().into_bytes())
+    }
+
+    /// Builds a `Redirect` to `address`, checking that it is a bare address as Postfix expects.
+    /// Fails with `ResponseError::InvalidAddress` for e.g. `"Name" <user@example.com>` or `<user@example.com>`.
+    pub fn redirect(address: impl Into<Vec<u8>>) -> Result<PolicyResponse, ResponseError> {
+        let address = address.into();
+        validate_address(&address)?;
+        Ok(PolicyResponse::Redirect(address))
+    }
+
+    /// Builds a `Bcc` to `address`, checking it like [`PolicyResponse::redirect`].
+    ///
+    /// [`PolicyResponse::redirect`]: enum.PolicyResponse.html#method.redirect
+    pub fn bcc(address: impl Into<Vec<u8>>) -> Result<PolicyResponse, ResponseError> {
+        let address = address.into();
+        validate_address(&address)?;
+        Ok(PolicyResponse::Bcc(address))
+    }
+
+    // Combinators picking a response from a condition, so `response()` can be written as one expression, e.g.
+    // `Ok(PolicyResponse::ok().or_reject_if(blocked, "blocked"))`.
+
+    /// Returns `then` if `condition` holds, otherwise `otherwise`.
+    pub fn decide(condition: bool, then: PolicyResponse, otherwise: PolicyResponse) -> PolicyResponse {
+        if condition {
+            then
+        } else {
+            otherwise
+        }
+    }
+
+    /// Returns `other` if `condition` holds, otherwise `self`.
+    pub fn or_if(self, condition: bool, other: PolicyResponse) -> PolicyResponse {
+        PolicyResponse::decide(condition, other, self)
+    }
+
+    /// Returns a `Reject` with `message` if `condition` holds, otherwise `self`.
+    pub fn or_reject_if(self, condition: bool, message: impl Into<Vec<u8>>) -> PolicyResponse {
+        if condition {
+            PolicyResponse::Reject(message.into())
+        } else {
+            self
+        }
+    }
+
+    /// Returns a `Defer` with `message` if `condition` holds, otherwise `self`.
+    pub fn or_defer_if(self, condition: bool, message: impl Into<Vec<u8>>) -> PolicyResponse {
+        if condition {
+            PolicyResponse::Defer(message.into())
+        } else {
+            self
+        }
+    }
+
+    /// Returns `self`, or `Dunno` if `condition` holds, e.g. to skip a check for trusted clients.
+    pub fn or_dunno_if(self, condition: bool) -> PolicyResponse {
+        self.or_if(condition, PolicyResponse::Dunno)
+    }
+
+    /// Builds a `Reject` whose message starts with the SMTP reply `code` and the enhanced status code `dsn`,
+    /// e.g. `550 5.7.1 text`. Postfix then uses these instead of its default codes.
+    ///
+    /// `code` must be a permanent error (5xx) and `dsn` must be an enhanced status code of class 5.
+    pub fn reject_with_status(code: u16, dsn: &str, text: &[u8]) -> Result<PolicyResponse, ResponseError> {
+        status_message(5, code, dsn, text).map(PolicyResponse::Reject)
+    }
+
+    /// Builds a `Defer` whose message starts with the SMTP reply `code` and the enhanced status code `dsn`,
+    /// e.g. `450 4.7.1 text`. Postfix then uses these instead of its default codes.
+    ///
+    /// `code` must be a temporary error (4xx) and `dsn` must be an enhanced status code of class 4.
+    pub fn defer_with_status(code: u16, dsn: &str, text: &[u8]) -> Result<PolicyResponse, ResponseError> {
+        status_message(4, code, dsn, text).map(PolicyResponse::Defer)
+    }
+
+    /// Builds a `Defer` advising the client to retry after `duration`, e.g. for greylisting:
+    /// `DEFER 4.7.1 Please retry after 60 seconds`.
+    ///
+    /// `duration` is rounded up to whole seconds, but is at least one second.
+    pub fn defer_retry_after(duration: Duration) -> PolicyResponse {
+        let mut seconds = duration.as_secs();
+        if duration.subsec_nanos() > 0 {
+            seconds = seconds.saturating_add(1);
+        }
+        let seconds = seconds.max(1);
+        PolicyResponse::Defer(format!("4.7.1 Please retry after {} seconds", seconds).into_bytes())
+    }
+
+    /// Returns the SMTP reply code this response results in, or `None` if it doesn't make Postfix send an error reply.
+    ///
+    /// If the message starts with a 4xx or 5xx reply code (see [`reject_with_status`] and `RejectCoded`), that code
+    /// is returned. Otherwise the Postfix defaults for access(5) actions apply:
+    ///
+    /// * `REJECT`: `access_map_reject_code`, 554
+    /// * `DEFER`: `access_map_defer_code`, 450
+    /// * `DEFER_IF_REJECT`, `DEFER_IF_PERMIT`: `defer_code`, 450, if the deferral takes effect
+    ///
+    /// All other actions return `None`. Note that the defaults are configurable in Postfix' main.cf.
+    ///
+    /// [`reject_with_status`]: #method.reject_with_status
+    pub fn effective_smtp_code(&self) -> Option<u16> {
+        let (default, class, message) = match self {
+            PolicyResponse::Reject(msg) => (554, 5, msg),
+            PolicyResponse::Defer(msg) => (450, 4, msg),
+            PolicyResponse::DeferIfReject(msg) | PolicyResponse::DeferIfPermit(msg) => (450, 4, msg),
+            PolicyResponse::RejectCoded { code, text } => return code.or_else(|| explicit_code(5, text)).or(Some(554)),
+            PolicyResponse::DeferCoded { code, text } => return code.or_else(|| explicit_code(4, text)).or(Some(450)),
+            PolicyResponse::Disconnect(_) => return Some(421),
+            _ => return None,
+        };
+        explicit_code(class, message).or(Some(default))
+    }
+
+    /// Parses an action as sent to the mail server (without the leading `action=`), e.g. `REJECT some message`.
+    ///
+    /// The action keyword is matched case-insensitively. Returns `None` for unknown actions and for `OK` and `DUNNO`
+    /// with a message, which can't be represented.
+    pub fn parse(action: &[u8]) -> Option<PolicyResponse> {
+        let (keyword, message) = match action.iter().position(|&c| c == b' ') {
+            Some(pos) => (&action[..pos], action[pos + 1..].to_vec()),
+            None => (action, Vec::new()),
+        };
+        let keyword = keyword.to_ascii_uppercase();
+        Some(match &keyword[..] {
+            b"OK" if message.is_empty() => PolicyResponse::Ok,
+            b"REJECT" => PolicyResponse::Reject(message),
+            b"DEFER" => PolicyResponse::Defer(message),
+            b"DEFER_IF_REJECT" => PolicyResponse::DeferIfReject(message),
+            b"DEFER_IF_PERMIT" => PolicyResponse::DeferIfPermit(message),
+            b"BCC" => PolicyResponse::Bcc(message),
+            b"DISCARD" => PolicyResponse::Discard(message),
+            b"DUNNO" if message.is_empty() => PolicyResponse::Dunno,
+            b"HOLD" => PolicyResponse::Hold(message),
+            b"REDIRECT" => PolicyResponse::Redirect(message),
+            b"INFO" => PolicyResponse::Info(message),
+            b"WARN" => PolicyResponse::Warn(message),
+            b"421" if message == b"4.7.0" => PolicyResponse::Disconnect(Vec::new()),
+            b"421" => PolicyResponse::Disconnect(message.strip_prefix(b"4.7.0 ").unwrap_or(&message).to_vec()),
+            _ => return None,
+        })
+    }
+
+    /// Parses a response configured by an administrator, e.g. a fallback action like
+    /// `DEFER_IF_PERMIT Service unavailable` read from a config file.
+    ///
+    /// Works like [`parse`] but ignores surrounding whitespace, and also checks that the response can be sent, so
+    /// e.g. a `REDIRECT` to an invalid address is reported when loading the config instead of on first use.
+    ///
+    /// [`parse`]: #method.parse
+    pub fn from_config_line(line: &str) -> Result<PolicyResponse, ResponseError> {
+        let line = line.trim();
+        let response = PolicyResponse::parse(line.as_bytes())
+            .ok_or_else(|| ResponseError::UnknownAction(line.as_bytes().to_vec()))?;
+        serialize_response(response.clone())?;
+        Ok(response)
+    }
 }
 
 /// Handler for policy requests.
@@ -63,9 +425,91 @@ pub trait PolicyRequestHandler<'l, ContextType, ErrorType> {
     ///
     /// [`handle_connection`]: fn.handle_connection.html
     fn response(self) -> Result<PolicyResponse, ErrorType>;
+    /// Called with every line of the request exactly as it was received, including the `\n` terminator,
+    /// before the line is processed. The empty line ending the request is passed as well, right before `response`
+    /// is called. Handlers that need to forward or hash requests byte for byte can capture them here.
+    /// The default implementation does nothing.
+    fn raw_line(&mut self, _line: &[u8]) {}
+    /// Called once when [`handle_connection`] starts handling a connection, before the first request is read.
+    /// As handler instances only live for one request, this gets the context instead, e.g. to open a log section.
+    /// The default implementation does nothing.
+    ///
+    /// [`handle_connection`]: fn.handle_connection.html
+    fn on_connection_start(_ctx: &'l ContextType) {}
+    /// Called once when [`handle_connection`] stops handling a connection, with the `result` it is about to return.
+    /// This is also called if handling failed. The default implementation does nothing.
+    ///
+    /// [`handle_connection`]: fn.handle_connection.html
+    fn on_connection_end(_ctx: &'l ContextType, _result: &Result<(), PostfixPolicyError<ErrorType>>) {}
 }
 
-fn serialize_response(resp: PolicyResponse) -> Vec<u8> {
+fn status_message(class: u16, code: u16, dsn: &str, text: &[u8]) -> Result<Vec<u8>, ResponseError> {
+    if code / 100 != class {
+        return Err(ResponseError::InvalidReplyCode(code));
+    }
+    let parts: Vec<&str> = dsn.split('.').collect();
+    let valid_dsn = parts.len() == 3
+        && parts[0] == class.to_string()
+        && parts[1..]
+            .iter()
+            .all(|part| (1..=3).contains(&part.len()) && part.bytes().all(|c| c.is_ascii_digit()));
+    if !valid_dsn {
+        return Err(ResponseError::InvalidEnhancedStatus(dsn.as_bytes().to_vec()));
+    }
+    let mut message = format!("{} {}", code, dsn).into_bytes();
+    if !text.is_empty() {
+        message.push(b' ');
+        message.extend_from_slice(text);
+    }
+    Ok(message)
+}
+
+/// Returns the reply code of class `class` a message starts with, like `550 text`.
+fn explicit_code(class: u16, message: &[u8]) -> Option<u16> {
+    let digits = message.get(..3)?;
+    if !digits.iter().all(u8::is_ascii_digit) || !matches!(message.get(3), None | Some(b' ')) {
+        return None;
+    }
+    let code = digits.iter().fold(0, |code, digit| code * 10 + u16::from(digit - b'0'));
+    if code / 100 != class {
+        return None;
+    }
+    Some(code)
+}
+
+fn validate_address(address: &[u8]) -> Result<(), ResponseError> {
+    let mut parts = address.split(|&c| c == b'@');
+    let valid = match (parts.next(), parts.next(), parts.next()) {
+        (Some(local), Some(domain), None) => {
+            !local.is_empty()
+                && !domain.is_empty()
+                && !address
+                    .iter()
+                    .any(|&c| c.is_ascii_whitespace() || c == b'<' || c == b'>')
+        }
+        _ => false,
+    };
+    if !valid {
+        return Err(ResponseError::InvalidAddress(address.to_vec()));
+    }
+    Ok(())
+}
+
+fn coded_message(class: u16, code: Option<u16>, text: Vec<u8>) -> Result<Vec<u8>, ResponseError> {
+    let code = match code {
+        None => return Ok(text),
+        Some(code) if code / 100 == class => code,
+        Some(code) => return Err(ResponseError::InvalidReplyCode(code)),
+    };
+    let mut message = code.to_string().into_bytes();
+    if !text.is_empty() {
+        message.push(b' ');
+        message.extend_from_slice(&text);
+    }
+    Ok(message)
+}
+
+fn serialize_response(resp: PolicyResponse) -> Result<Vec<u8>, ResponseError> {
     let mut message = Vec::new();
     let action: &[u8] = match resp {
         PolicyResponse::Ok => b"OK",
@@ -86,6 +530,7 @@ fn serialize_response(resp: PolicyResponse) -> Vec<u8> {
             b"DEFER_IF_PERMIT"
         }
         PolicyResponse::Bcc(email) => {
+            validate_address(&email)?;
             message = email;
             b"BCC"
         }
@@ -99,6 +544,7 @@ fn serialize_response(resp: PolicyResponse) -> Vec<u8> {
             b"HOLD"
         }
         PolicyResponse::Redirect(dst) => {
+            validate_address(&dst)?;
             message = dst;
             b"REDIRECT"
         }
@@ -110,299 +556,3799 @@ fn serialize_response(resp: PolicyResponse) -> Vec<u8> {
             message = msg;
             b"WARN"
         }
+        PolicyResponse::RejectCoded { code, text } => {
+            message = coded_message(5, code, text)?;
+            b"REJECT"
+        }
+        PolicyResponse::DeferCoded { code, text } => {
+            message = coded_message(4, code, text)?;
+            b"DEFER"
+        }
+        PolicyResponse::Disconnect(msg) => {
+            message = b"4.7.0".to_vec();
+            if !msg.is_empty() {
+                message.push(b' ');
+                message.extend_from_slice(&msg);
+            }
+            b"421"
+        }
     };
+    if message.contains(&b'\n') {
+        return Err(ResponseError::MessageContainsNewline(message));
+    }
     let mut resp = Vec::from(action);
     if !message.is_empty() {
         resp.push(b' ');
         resp.extend_from_slice(&message);
     }
-    resp
+    Ok(resp)
 }
 
 #[test]
 fn test_serialize_response() {
-    assert_eq!(b"OK"[..], serialize_response(PolicyResponse::Ok)[..]);
+    assert_eq!(b"OK"[..], serialize_response(PolicyResponse::Ok).unwrap()[..]);
     assert_eq!(
         b"REJECT"[..],
-        serialize_response(PolicyResponse::Reject(Vec::new()))[..]
+        serialize_response(PolicyResponse::Reject(Vec::new())).unwrap()[..]
     );
     assert_eq!(
         b"REJECT asdf"[..],
-        serialize_response(PolicyResponse::Reject(b"asdf".to_vec()))[..]
+        serialize_response(PolicyResponse::Reject(b"asdf".to_vec())).unwrap()[..]
+    );
+    assert_eq!(
+        b"DEFER"[..],
+        serialize_response(PolicyResponse::Defer(Vec::new())).unwrap()[..]
     );
-    assert_eq!(b"DEFER"[..], serialize_response(PolicyResponse::Defer(Vec::new()))[..]);
     assert_eq!(
         b"DEFER fdas"[..],
-        serialize_response(PolicyResponse::Defer(b"fdas".to_vec()))[..]
+        serialize_response(PolicyResponse::Defer(b"fdas".to_vec())).unwrap()[..]
     );
     assert_eq!(
         b"DEFER_IF_REJECT"[..],
-        serialize_response(PolicyResponse::DeferIfReject(Vec::new()))[..]
+        serialize_response(PolicyResponse::DeferIfReject(Vec::new())).unwrap()[..]
     );
     assert_eq!(
         b"DEFER_IF_REJECT blblblbl"[..],
-        serialize_response(PolicyResponse::DeferIfReject(b"blblblbl".to_vec()))[..]
+        serialize_response(PolicyResponse::DeferIfReject(b"blblblbl".to_vec())).unwrap()[..]
     );
     assert_eq!(
         b"DEFER_IF_PERMIT"[..],
-        serialize_response(PolicyResponse::DeferIfPermit(Vec::new()))[..]
+        serialize_response(PolicyResponse::DeferIfPermit(Vec::new())).unwrap()[..]
     );
     assert_eq!(
         b"DEFER_IF_PERMIT gsdk jf"[..],
-        serialize_response(PolicyResponse::DeferIfPermit(b"gsdk jf".to_vec()))[..]
+        serialize_response(PolicyResponse::DeferIfPermit(b"gsdk jf".to_vec())).unwrap()[..]
     );
     assert_eq!(
         b"BCC a@b.c"[..],
-        serialize_response(PolicyResponse::Bcc(b"a@b.c".to_vec()))[..]
+        serialize_response(PolicyResponse::Bcc(b"a@b.c".to_vec())).unwrap()[..]
     );
     assert_eq!(
         b"DISCARD"[..],
-        serialize_response(PolicyResponse::Discard(Vec::new()))[..]
+        serialize_response(PolicyResponse::Discard(Vec::new())).unwrap()[..]
     );
     assert_eq!(
         b"DISCARD asdffdas"[..],
-        serialize_response(PolicyResponse::Discard(b"asdffdas".to_vec()))[..]
+        serialize_response(PolicyResponse::Discard(b"asdffdas".to_vec())).unwrap()[..]
+    );
+    assert_eq!(b"DUNNO"[..], serialize_response(PolicyResponse::Dunno).unwrap()[..]);
+    assert_eq!(
+        b"HOLD"[..],
+        serialize_response(PolicyResponse::Hold(Vec::new())).unwrap()[..]
     );
-    assert_eq!(b"DUNNO"[..], serialize_response(PolicyResponse::Dunno)[..]);
-    assert_eq!(b"HOLD"[..], serialize_response(PolicyResponse::Hold(Vec::new()))[..]);
     assert_eq!(
         b"HOLD cmn,sd"[..],
-        serialize_response(PolicyResponse::Hold(b"cmn,sd".to_vec()))[..]
+        serialize_response(PolicyResponse::Hold(b"cmn,sd".to_vec())).unwrap()[..]
     );
     assert_eq!(
         b"REDIRECT a@b.c"[..],
-        serialize_response(PolicyResponse::Redirect(b"a@b.c".to_vec()))[..]
+        serialize_response(PolicyResponse::Redirect(b"a@b.c".to_vec())).unwrap()[..]
     );
     assert_eq!(
         b"INFO some message trololol"[..],
-        serialize_response(PolicyResponse::Info(b"some message trololol".to_vec()))[..]
+        serialize_response(PolicyResponse::Info(b"some message trololol".to_vec())).unwrap()[..]
     );
     assert_eq!(
         b"WARN writing something to logs because logging is great and everyone should log everything"[..],
         serialize_response(PolicyResponse::Warn(
             b"writing something to logs because logging is great and everyone should log everything".to_vec()
-        ))[..]
+        ))
+        .unwrap()[..]
     );
 }
 
-/**
- Handles a connection to the mail server.
+#[test]
+fn test_serialize_coded_response() {
+    assert_eq!(
+        b"REJECT 550 no such user"[..],
+        serialize_response(PolicyResponse::RejectCoded {
+            code: Some(550),
+            text: b"no such user".to_vec()
+        })
+        .unwrap()[..]
+    );
+    assert_eq!(
+        b"REJECT no such user"[..],
+        serialize_response(PolicyResponse::RejectCoded {
+            code: None,
+            text: b"no such user".to_vec()
+        })
+        .unwrap()[..]
+    );
+    assert_eq!(
+        b"REJECT 554"[..],
+        serialize_response(PolicyResponse::RejectCoded {
+            code: Some(554),
+            text: Vec::new()
+        })
+        .unwrap()[..]
+    );
+    assert_eq!(
+        b"DEFER 421 try later"[..],
+        serialize_response(PolicyResponse::DeferCoded {
+            code: Some(421),
+            text: b"try later".to_vec()
+        })
+        .unwrap()[..]
+    );
+    assert_eq!(
+        serialize_response(PolicyResponse::RejectCoded {
+            code: Some(450),
+            text: Vec::new()
+        }),
+        Err(ResponseError::InvalidReplyCode(450))
+    );
+    assert_eq!(
+        serialize_response(PolicyResponse::DeferCoded {
+            code: Some(4500),
+            text: Vec::new()
+        }),
+        Err(ResponseError::InvalidReplyCode(4500))
+    );
+}
 
- `socket` is the connection to the mail server and `ctx` is the Context, passed through each time `PolicyRequestHandler::new` is called.\
- It will create a new instance of the given [`PolicyRequestHandler`] for every request.\
- Might handle multiple policy requests before returning.
- ## Example
- ```norun
-     let listener = UnixListener::bind(socket_path).expect("Could not bind UNIX socket");
-     for conn in listener.incoming() {
-         let mut conn = conn?;
-         let cfg_ref = &config;
-         thread::spawn(move || {
-             if let Err(e) = handle_connection::<MyHandlerType, _, _, _>(&mut conn, cfg_ref) {
-                 println!("handle_connection failed: {:?}", e);
-             };
-         });
-     }
- ```
- [`PolicyRequestHandler`]: trait.PolicyRequestHandler.html
-*/
-pub fn handle_connection<'socket, 'ctx, HandlerType, ContextType, ErrorType, SocketType>(
-    mut socket: &'socket SocketType,
-    ctx: &'ctx ContextType,
-) -> Result<(), PostfixPolicyError<ErrorType>>
-where
-    HandlerType: PolicyRequestHandler<'ctx, ContextType, ErrorType>,
-    &'socket SocketType: Read + Write,
-{
-    let mut handler: HandlerType = HandlerType::new(ctx);
-    let mut reader = BufReader::new(socket);
+#[test]
+fn test_serialize_disconnect() {
+    assert_eq!(
+        b"421 4.7.0 too many errors"[..],
+        serialize_response(PolicyResponse::Disconnect(b"too many errors".to_vec())).unwrap()[..]
+    );
+    assert_eq!(
+        b"421 4.7.0"[..],
+        serialize_response(PolicyResponse::Disconnect(Vec::new())).unwrap()[..]
+    );
+}
 
-    loop {
-        let mut buf: Vec<u8> = vec![];
-        if reader.read_until(b'\n', &mut buf)? == 0 {
-            return Ok(());
-        }
+#[test]
+fn test_serialize_response_newline() {
+    assert_eq!(
+        serialize_response(PolicyResponse::Reject(b"a\n\nb".to_vec())),
+        Err(ResponseError::MessageContainsNewline(b"a\n\nb".to_vec()))
+    );
+    assert_eq!(
+        serialize_response(PolicyResponse::Warn(b"a\n".to_vec())),
+        Err(ResponseError::MessageContainsNewline(b"a\n".to_vec()))
+    );
+    assert_eq!(
+        serialize_response(PolicyResponse::RejectCoded {
+            code: Some(550),
+            text: b"a\n\nb".to_vec()
+        }),
+        Err(ResponseError::MessageContainsNewline(b"550 a\n\nb".to_vec()))
+    );
+}
 
-        if buf == b"\n" {
-            let result = match handler.response() {
-                Ok(result) => result,
-                Err(e) => return Err(PostfixPolicyError::HandlerError(e)),
-            };
-            socket.write_all(b"action=")?;
-            socket.write_all(&serialize_response(result))?;
-            socket.write_all(b"\n\n")?;
-            socket.flush()?;
-            handler = HandlerType::new(ctx);
-            continue;
-        }
+#[test]
+fn test_redirect_constructor() {
+    assert_eq!(
+        PolicyResponse::redirect("user@example.com"),
+        Ok(PolicyResponse::Redirect(b"user@example.com".to_vec()))
+    );
+    assert_eq!(
+        PolicyResponse::redirect("\"Name\" <user@example.com>"),
+        Err(ResponseError::InvalidAddress(b"\"Name\" <user@example.com>".to_vec()))
+    );
+    assert_eq!(
+        PolicyResponse::redirect("<user@example.com>"),
+        Err(ResponseError::InvalidAddress(b"<user@example.com>".to_vec()))
+    );
+    assert_eq!(
+        PolicyResponse::bcc("archive@example.com"),
+        Ok(PolicyResponse::Bcc(b"archive@example.com".to_vec()))
+    );
+}
 
-        match buf.iter().position(|&c| c == b'=') {
-            None => return Err(PostfixPolicyError::ProtocolError(buf)),
-            Some(pos) => {
-                let (left, mut right) = buf.split_at(pos);
-                if left.is_empty() || right.len() < 2 {
-                    return Err(PostfixPolicyError::ProtocolError(buf));
-                }
-                right = &right[1..right.len() - 1];
-                if let Some(error) = handler.attribute(left, right) {
-                    return Err(PostfixPolicyError::HandlerError(error));
-                }
-            }
-        }
+#[test]
+fn test_serialize_response_address() {
+    assert_eq!(
+        b"BCC user@example.com"[..],
+        serialize_response(PolicyResponse::Bcc(b"user@example.com".to_vec())).unwrap()[..]
+    );
+    assert_eq!(
+        b"REDIRECT user@example.com"[..],
+        serialize_response(PolicyResponse::Redirect(b"user@example.com".to_vec())).unwrap()[..]
+    );
+    for address in [
+        &b"notanemail"[..],
+        b"a@b@c",
+        b"@example.com",
+        b"user@",
+        b"",
+        b"us er@example.com",
+        b"a@b\tc",
+        b"<user@example.com>",
+    ]
+    .iter()
+    {
+        assert_eq!(
+            serialize_response(PolicyResponse::Bcc(address.to_vec())),
+            Err(ResponseError::InvalidAddress(address.to_vec()))
+        );
+        assert_eq!(
+            serialize_response(PolicyResponse::Redirect(address.to_vec())),
+            Err(ResponseError::InvalidAddress(address.to_vec()))
+        );
     }
 }
 
-/// provides helpers for testing
-pub mod test_helper {
-    use super::{handle_connection, PolicyRequestHandler, PostfixPolicyError};
-    use std::cell::RefCell;
-    use std::io::Cursor;
-    use std::io::{Read, Write};
+#[test]
+fn test_str_constructors() {
+    assert_eq!(b"OK"[..], serialize_response(PolicyResponse::ok()).unwrap()[..]);
+    assert_eq!(
+        b"REJECT blocked"[..],
+        serialize_response(PolicyResponse::reject_str("blocked")).unwrap()[..]
+    );
+    assert_eq!(
+        b"DEFER try later"[..],
+        serialize_response(PolicyResponse::defer_str(String::from("try later"))).unwrap()[..]
+    );
+    assert_eq!(
+        PolicyResponse::defer_if_reject_str("a"),
+        PolicyResponse::DeferIfReject(b"a".to_vec())
+    );
+    assert_eq!(
+        PolicyResponse::defer_if_permit_str("a"),
+        PolicyResponse::DeferIfPermit(b"a".to_vec())
+    );
+    assert_eq!(PolicyResponse::discard_str("a"), PolicyResponse::Discard(b"a".to_vec()));
+    assert_eq!(PolicyResponse::hold_str("a"), PolicyResponse::Hold(b"a".to_vec()));
+    assert_eq!(PolicyResponse::info_str("a"), PolicyResponse::Info(b"a".to_vec()));
+    assert_eq!(PolicyResponse::warn_str(""), PolicyResponse::Warn(Vec::new()));
+}
 
-    /// A Dummy Socket, implementing `Read` and `Write`. It is give an `&[u8]` input which will be returned by `read` calls. After using it, the complete written output can be obtained by calling `get_output`.
-    pub struct DummySocket<'lt> {
-        input: RefCell<Cursor<&'lt [u8]>>,
-        output: RefCell<Vec<u8>>,
+#[test]
+fn test_response_with_status() {
+    assert_eq!(
+        b"REJECT 550 5.7.1 go away"[..],
+        serialize_response(PolicyResponse::reject_with_status(550, "5.7.1", b"go away").unwrap()).unwrap()[..]
+    );
+    assert_eq!(
+        b"REJECT 554 5.7.1"[..],
+        serialize_response(PolicyResponse::reject_with_status(554, "5.7.1", b"").unwrap()).unwrap()[..]
+    );
+    assert_eq!(
+        b"DEFER 450 4.7.1 try again later"[..],
+        serialize_response(PolicyResponse::defer_with_status(450, "4.7.1", b"try again later").unwrap()).unwrap()[..]
+    );
+    assert_eq!(
+        PolicyResponse::reject_with_status(450, "5.7.1", b""),
+        Err(ResponseError::InvalidReplyCode(450))
+    );
+    assert_eq!(
+        PolicyResponse::defer_with_status(600, "4.7.1", b""),
+        Err(ResponseError::InvalidReplyCode(600))
+    );
+    for dsn in ["4.7.1", "5.7", "5.7.1.1", "5.1000.1", "5..1", "5.a.1", ""].iter() {
+        assert_eq!(
+            PolicyResponse::reject_with_status(550, dsn, b""),
+            Err(ResponseError::InvalidEnhancedStatus(dsn.as_bytes().to_vec()))
+        );
     }
+}
 
-    impl<'lt> DummySocket<'lt> {
-        /// creates a new `DummySocket` instance. The data given as `input` can be read by calling `read`.
-        pub fn new(input: &'lt [u8]) -> Self {
-            DummySocket {
-                input: RefCell::new(Cursor::new(input)),
-                output: RefCell::new(vec![]),
-            }
-        }
-
-        /// returns the output written into this `DummySocket`.
+#[test]
+fn test_defer_retry_after() {
+    assert_eq!(
+        b"DEFER 4.7.1 Please retry after 60 seconds"[..],
+        serialize_response(PolicyResponse::defer_retry_after(Duration::from_secs(60))).unwrap()[..]
+    );
+    assert_eq!(
+        b"DEFER 4.7.1 Please retry after 1 seconds"[..],
+        serialize_response(PolicyResponse::defer_retry_after(Duration::from_millis(300))).unwrap()[..]
+    );
+    assert_eq!(
+        b"DEFER 4.7.1 Please retry after 61 seconds"[..],
+        serialize_response(PolicyResponse::defer_retry_after(Duration::from_millis(60_001))).unwrap()[..]
+    );
+    assert_eq!(
+        b"DEFER 4.7.1 Please retry after 18446744073709551615 seconds"[..],
+        serialize_response(PolicyResponse::defer_retry_after(Duration::new(u64::MAX, 1))).unwrap()[..]
+    );
+}
+
+#[test]
+fn test_effective_smtp_code() {
+    assert_eq!(PolicyResponse::Reject(Vec::new()).effective_smtp_code(), Some(554));
+    assert_eq!(
+        PolicyResponse::Reject(b"go away".to_vec()).effective_smtp_code(),
+        Some(554)
+    );
+    assert_eq!(
+        PolicyResponse::Reject(b"550 5.7.1 go away".to_vec()).effective_smtp_code(),
+        Some(550)
+    );
+    assert_eq!(PolicyResponse::Reject(b"550".to_vec()).effective_smtp_code(), Some(550));
+    assert_eq!(
+        PolicyResponse::Reject(b"5500 go".to_vec()).effective_smtp_code(),
+        Some(554)
+    );
+    assert_eq!(
+        PolicyResponse::Reject(b"450 go".to_vec()).effective_smtp_code(),
+        Some(554)
+    );
+    assert_eq!(PolicyResponse::Defer(Vec::new()).effective_smtp_code(), Some(450));
+    assert_eq!(
+        PolicyResponse::Defer(b"421 later".to_vec()).effective_smtp_code(),
+        Some(421)
+    );
+    assert_eq!(
+        PolicyResponse::DeferIfPermit(Vec::new()).effective_smtp_code(),
+        Some(450)
+    );
+    assert_eq!(
+        PolicyResponse::RejectCoded {
+            code: Some(551),
+            text: Vec::new()
+        }
+        .effective_smtp_code(),
+        Some(551)
+    );
+    assert_eq!(
+        PolicyResponse::DeferCoded {
+            code: None,
+            text: Vec::new()
+        }
+        .effective_smtp_code(),
+        Some(450)
+    );
+    assert_eq!(PolicyResponse::Disconnect(Vec::new()).effective_smtp_code(), Some(421));
+    assert_eq!(PolicyResponse::Ok.effective_smtp_code(), None);
+    assert_eq!(PolicyResponse::Dunno.effective_smtp_code(), None);
+    assert_eq!(PolicyResponse::Hold(b"550 x".to_vec()).effective_smtp_code(), None);
+}
+
+#[test]
+fn test_parse_response() {
+    assert_eq!(PolicyResponse::parse(b"OK"), Some(PolicyResponse::Ok));
+    assert_eq!(PolicyResponse::parse(b"dunno"), Some(PolicyResponse::Dunno));
+    assert_eq!(
+        PolicyResponse::parse(b"REJECT"),
+        Some(PolicyResponse::Reject(Vec::new()))
+    );
+    assert_eq!(
+        PolicyResponse::parse(b"DEFER_IF_PERMIT gsdk jf"),
+        Some(PolicyResponse::DeferIfPermit(b"gsdk jf".to_vec()))
+    );
+    assert_eq!(
+        PolicyResponse::parse(b"REDIRECT a@b.c"),
+        Some(PolicyResponse::Redirect(b"a@b.c".to_vec()))
+    );
+    assert_eq!(
+        PolicyResponse::parse(b"421 4.7.0 go away"),
+        Some(PolicyResponse::Disconnect(b"go away".to_vec()))
+    );
+    assert_eq!(
+        PolicyResponse::parse(b"421 4.7.0"),
+        Some(PolicyResponse::Disconnect(Vec::new()))
+    );
+    assert_eq!(PolicyResponse::parse(b"OK text"), None);
+    assert_eq!(PolicyResponse::parse(b"ACCEPT"), None);
+    assert_eq!(PolicyResponse::parse(b""), None);
+}
+
+#[test]
+fn test_response_from_config_line() {
+    assert_eq!(
+        PolicyResponse::from_config_line("DEFER_IF_PERMIT Service unavailable"),
+        Ok(PolicyResponse::DeferIfPermit(b"Service unavailable".to_vec()))
+    );
+    assert_eq!(
+        PolicyResponse::from_config_line("  reject 5.7.1 No thanks\n"),
+        Ok(PolicyResponse::Reject(b"5.7.1 No thanks".to_vec()))
+    );
+    assert_eq!(PolicyResponse::from_config_line("DUNNO"), Ok(PolicyResponse::Dunno));
+    assert_eq!(
+        PolicyResponse::from_config_line("ACCEPT everything"),
+        Err(ResponseError::UnknownAction(b"ACCEPT everything".to_vec()))
+    );
+    assert_eq!(
+        PolicyResponse::from_config_line("REDIRECT nobody"),
+        Err(ResponseError::InvalidAddress(b"nobody".to_vec()))
+    );
+}
+
+#[test]
+fn test_response_combinators() {
+    assert_eq!(
+        PolicyResponse::decide(true, PolicyResponse::Ok, PolicyResponse::Dunno),
+        PolicyResponse::Ok
+    );
+    assert_eq!(
+        PolicyResponse::decide(false, PolicyResponse::Ok, PolicyResponse::Dunno),
+        PolicyResponse::Dunno
+    );
+    assert_eq!(
+        PolicyResponse::Dunno.or_if(true, PolicyResponse::Ok),
+        PolicyResponse::Ok
+    );
+    assert_eq!(
+        PolicyResponse::Dunno.or_if(false, PolicyResponse::Ok),
+        PolicyResponse::Dunno
+    );
+    assert_eq!(
+        PolicyResponse::ok().or_reject_if(true, "blocked"),
+        PolicyResponse::Reject(b"blocked".to_vec())
+    );
+    assert_eq!(PolicyResponse::ok().or_reject_if(false, "blocked"), PolicyResponse::Ok);
+    assert_eq!(
+        PolicyResponse::Dunno.or_defer_if(true, &b"later"[..]),
+        PolicyResponse::Defer(b"later".to_vec())
+    );
+    assert_eq!(
+        PolicyResponse::Dunno.or_defer_if(false, &b"later"[..]),
+        PolicyResponse::Dunno
+    );
+    assert_eq!(
+        PolicyResponse::reject_str("no").or_dunno_if(true),
+        PolicyResponse::Dunno
+    );
+    assert_eq!(
+        PolicyResponse::reject_str("no").or_dunno_if(false),
+        PolicyResponse::reject_str("no")
+    );
+
+    // conditions are applied in order, the last matching one wins
+    let response = PolicyResponse::Dunno
+        .or_defer_if(true, "greylisted")
+        .or_reject_if(true, "blocked");
+    assert_eq!(response, PolicyResponse::Reject(b"blocked".to_vec()));
+}
+
+#[test]
+fn test_response_is_terminal() {
+    for response in [
+        PolicyResponse::Ok,
+        PolicyResponse::Reject(Vec::new()),
+        PolicyResponse::Defer(Vec::new()),
+        PolicyResponse::Discard(Vec::new()),
+        PolicyResponse::RejectCoded {
+            code: None,
+            text: Vec::new(),
+        },
+        PolicyResponse::DeferCoded {
+            code: None,
+            text: Vec::new(),
+        },
+        PolicyResponse::Disconnect(Vec::new()),
+    ] {
+        assert!(response.is_terminal(), "{:?}", response);
+    }
+    for response in [
+        PolicyResponse::Dunno,
+        PolicyResponse::Info(Vec::new()),
+        PolicyResponse::Warn(Vec::new()),
+        PolicyResponse::Hold(Vec::new()),
+        PolicyResponse::Redirect(b"a@b.c".to_vec()),
+        PolicyResponse::Bcc(b"a@b.c".to_vec()),
+        PolicyResponse::DeferIfReject(Vec::new()),
+        PolicyResponse::DeferIfPermit(Vec::new()),
+    ] {
+        assert!(!response.is_terminal(), "{:?}", response);
+    }
+}
+
+#[test]
+fn test_response_classification() {
+    let cases = [
+        (PolicyResponse::Ok, true, false, false),
+        (PolicyResponse::Reject(Vec::new()), false, true, false),
+        (PolicyResponse::Defer(Vec::new()), false, true, false),
+        (PolicyResponse::DeferIfReject(Vec::new()), false, false, false),
+        (PolicyResponse::DeferIfPermit(Vec::new()), false, false, false),
+        (PolicyResponse::Bcc(b"a@b.c".to_vec()), false, false, false),
+        (PolicyResponse::Discard(Vec::new()), true, false, false),
+        (PolicyResponse::Dunno, false, false, true),
+        (PolicyResponse::Hold(Vec::new()), false, false, false),
+        (PolicyResponse::Redirect(b"a@b.c".to_vec()), false, false, false),
+        (PolicyResponse::Info(b"x".to_vec()), false, false, true),
+        (PolicyResponse::Warn(b"x".to_vec()), false, false, true),
+        (PolicyResponse::Disconnect(Vec::new()), false, true, false),
+        (
+            PolicyResponse::RejectCoded {
+                code: Some(550),
+                text: Vec::new(),
+            },
+            false,
+            true,
+            false,
+        ),
+        (
+            PolicyResponse::DeferCoded {
+                code: None,
+                text: Vec::new(),
+            },
+            false,
+            true,
+            false,
+        ),
+    ];
+    for (resp, accept, reject, passthrough) in cases.iter() {
+        assert_eq!(resp.is_accept(), *accept, "is_accept({:?})", resp);
+        assert_eq!(resp.is_reject(), *reject, "is_reject({:?})", resp);
+        assert_eq!(resp.is_passthrough(), *passthrough, "is_passthrough({:?})", resp);
+    }
+}
+
+/// The access(5) contract in one place: for every `PolicyResponse` variant, the exact bytes sent to the server and
+/// the response parsing the action back gives.
+#[test]
+fn test_response_conformance() {
+    let table: Vec<(PolicyResponse, &[u8], PolicyResponse)> = vec![
+        (PolicyResponse::Ok, b"action=OK\n\n", PolicyResponse::Ok),
+        (PolicyResponse::Dunno, b"action=DUNNO\n\n", PolicyResponse::Dunno),
+        (
+            PolicyResponse::Reject(b"go away".to_vec()),
+            b"action=REJECT go away\n\n",
+            PolicyResponse::Reject(b"go away".to_vec()),
+        ),
+        (
+            PolicyResponse::Reject(Vec::new()),
+            b"action=REJECT\n\n",
+            PolicyResponse::Reject(Vec::new()),
+        ),
+        (
+            PolicyResponse::Defer(b"later".to_vec()),
+            b"action=DEFER later\n\n",
+            PolicyResponse::Defer(b"later".to_vec()),
+        ),
+        (
+            PolicyResponse::DeferIfReject(b"maybe".to_vec()),
+            b"action=DEFER_IF_REJECT maybe\n\n",
+            PolicyResponse::DeferIfReject(b"maybe".to_vec()),
+        ),
+        (
+            PolicyResponse::DeferIfPermit(b"maybe".to_vec()),
+            b"action=DEFER_IF_PERMIT maybe\n\n",
+            PolicyResponse::DeferIfPermit(b"maybe".to_vec()),
+        ),
+        (
+            PolicyResponse::Bcc(b"archive@example.com".to_vec()),
+            b"action=BCC archive@example.com\n\n",
+            PolicyResponse::Bcc(b"archive@example.com".to_vec()),
+        ),
+        (
+            PolicyResponse::Discard(b"spam".to_vec()),
+            b"action=DISCARD spam\n\n",
+            PolicyResponse::Discard(b"spam".to_vec()),
+        ),
+        (
+            PolicyResponse::Hold(b"review".to_vec()),
+            b"action=HOLD review\n\n",
+            PolicyResponse::Hold(b"review".to_vec()),
+        ),
+        (
+            PolicyResponse::Redirect(b"quarantine@example.com".to_vec()),
+            b"action=REDIRECT quarantine@example.com\n\n",
+            PolicyResponse::Redirect(b"quarantine@example.com".to_vec()),
+        ),
+        (
+            PolicyResponse::Info(b"seen".to_vec()),
+            b"action=INFO seen\n\n",
+            PolicyResponse::Info(b"seen".to_vec()),
+        ),
+        (
+            PolicyResponse::Warn(b"odd".to_vec()),
+            b"action=WARN odd\n\n",
+            PolicyResponse::Warn(b"odd".to_vec()),
+        ),
+        // the coded variants are sent as plain REJECT and DEFER, so they come back with the code in the message
+        (
+            PolicyResponse::RejectCoded {
+                code: Some(550),
+                text: b"5.7.1 no".to_vec(),
+            },
+            b"action=REJECT 550 5.7.1 no\n\n",
+            PolicyResponse::Reject(b"550 5.7.1 no".to_vec()),
+        ),
+        (
+            PolicyResponse::RejectCoded {
+                code: None,
+                text: b"no".to_vec(),
+            },
+            b"action=REJECT no\n\n",
+            PolicyResponse::Reject(b"no".to_vec()),
+        ),
+        (
+            PolicyResponse::DeferCoded {
+                code: Some(451),
+                text: b"4.3.0 later".to_vec(),
+            },
+            b"action=DEFER 451 4.3.0 later\n\n",
+            PolicyResponse::Defer(b"451 4.3.0 later".to_vec()),
+        ),
+        (
+            PolicyResponse::Disconnect(b"bye".to_vec()),
+            b"action=421 4.7.0 bye\n\n",
+            PolicyResponse::Disconnect(b"bye".to_vec()),
+        ),
+    ];
+
+    // Fails to compile once a variant is added, until it gets an index here. The test then fails until the table
+    // has a row for it.
+    fn variant_index(response: &PolicyResponse) -> usize {
+        match response {
+            PolicyResponse::Ok => 0,
+            PolicyResponse::Reject(_) => 1,
+            PolicyResponse::Defer(_) => 2,
+            PolicyResponse::DeferIfReject(_) => 3,
+            PolicyResponse::DeferIfPermit(_) => 4,
+            PolicyResponse::Bcc(_) => 5,
+            PolicyResponse::Discard(_) => 6,
+            PolicyResponse::Dunno => 7,
+            PolicyResponse::Hold(_) => 8,
+            PolicyResponse::Redirect(_) => 9,
+            PolicyResponse::Info(_) => 10,
+            PolicyResponse::Warn(_) => 11,
+            PolicyResponse::RejectCoded { .. } => 12,
+            PolicyResponse::DeferCoded { .. } => 13,
+            PolicyResponse::Disconnect(_) => 14,
+        }
+    }
+    const VARIANTS: usize = 15;
+
+    let mut covered = [false; VARIANTS];
+    for (response, wire, parsed) in table {
+        covered[variant_index(&response)] = true;
+        let mut output = Vec::new();
+        flush_response::<_, ()>(&mut output, response.clone()).unwrap();
+        assert_eq!(output, wire, "serializing {:?}", response);
+        let action = &wire[b"action=".len()..wire.len() - 2];
+        assert_eq!(
+            PolicyResponse::parse(action),
+            Some(parsed.clone()),
+            "parsing {:?}",
+            response
+        );
+        // what was parsed back is sent the same way again
+        let mut again = Vec::new();
+        flush_response::<_, ()>(&mut again, parsed).unwrap();
+        assert_eq!(again, wire, "serializing {:?} parsed back", response);
+    }
+    for (index, covered) in covered.iter().enumerate() {
+        assert!(covered, "no conformance row for variant {}", index);
+    }
+}
+
+/**
+ Handles a connection to the mail server.
+
+ `socket` is the connection to the mail server and `ctx` is the Context, passed through each time `PolicyRequestHandler::new` is called.\
+ It will create a new instance of the given [`PolicyRequestHandler`] for every request.\
+ Might handle multiple policy requests before returning.
+
+ Attributes are passed to the handler line by line: only the current line is kept in memory, nothing of a request is
+ retained after its attribute was passed on. So the memory used doesn't grow with the number of attributes in a
+ request unless the handler itself stores them.
+
+ `socket` has to be in blocking mode. If reading returns `std::io::ErrorKind::WouldBlock`, handling is aborted with
+ `PostfixPolicyError::WouldBlock`.
+ ## Example
+ ```norun
+     let listener = UnixListener::bind(socket_path).expect("Could not bind UNIX socket");
+     for conn in listener.incoming() {
+         let mut conn = conn?;
+         let cfg_ref = &config;
+         thread::spawn(move || {
+             if let Err(e) = handle_connection::<MyHandlerType, _, _, _>(&mut conn, cfg_ref) {
+                 println!("handle_connection failed: {:?}", e);
+             };
+         });
+     }
+ ```
+ [`PolicyRequestHandler`]: trait.PolicyRequestHandler.html
+*/
+pub fn handle_connection<'socket, 'ctx, HandlerType, ContextType, ErrorType, SocketType>(
+    socket: &'socket SocketType,
+    ctx: &'ctx ContextType,
+) -> Result<(), PostfixPolicyError<ErrorType>>
+where
+    HandlerType: PolicyRequestHandler<'ctx, ContextType, ErrorType>,
+    &'socket SocketType: Read + Write,
+{
+    handle_connection_split::<HandlerType, ContextType, ErrorType, _, _>(BufReader::new(socket), socket, ctx)
+}
+
+/// Handles a connection to the mail server like [`handle_connection`], but reads the requests from `reader` and
+/// writes the responses to `writer`, which can be different handles. This allows e.g. talking to the server via
+/// stdin and stdout, or teeing the responses into a log.
+///
+/// [`handle_connection`]: fn.handle_connection.html
+pub fn handle_connection_split<'ctx, HandlerType, ContextType, ErrorType, ReaderType, WriterType>(
+    mut reader: ReaderType,
+    mut writer: WriterType,
+    ctx: &'ctx ContextType,
+) -> Result<(), PostfixPolicyError<ErrorType>>
+where
+    HandlerType: PolicyRequestHandler<'ctx, ContextType, ErrorType>,
+    ReaderType: BufRead,
+    WriterType: Write,
+{
+    serve_requests::<HandlerType, _, _, _, _, _, _>(
+        &mut reader,
+        &mut writer,
+        ctx,
+        &Limits::default(),
+        &mut NoMetrics,
+        &SystemClock,
+        None,
+    )
+}
+
+/**
+ Handles exactly one request: reads it from `reader`, passes it to a new `HandlerType` and writes the response to
+ `writer`. Returns the response that was sent, or `None` if `reader` was at EOF.
+
+ This is the step [`handle_connection`] repeats for every request, for callers running their own loop, e.g. to
+ check a flag between requests. `reader` has to be kept between calls, as it may have buffered the beginning of the
+ next request already. The connection events `on_connection_start` and `on_connection_end` of
+ [`PolicyRequestHandler`] aren't called, as there is no connection from the point of view of this function.
+
+ If the request is malformed, `PostfixPolicyError::ProtocolError` is returned and the connection should be closed.
+
+ ## Example
+ ```norun
+     let mut reader = BufReader::new(&conn);
+     while let Some(response) = handle_one::<MyHandlerType, _, _, _, _>(&mut reader, &mut &conn, &config)? {
+         println!("answered {:?}", response);
+     }
+ ```
+ [`handle_connection`]: fn.handle_connection.html
+ [`PolicyRequestHandler`]: trait.PolicyRequestHandler.html
+*/
+pub fn handle_one<'ctx, HandlerType, ContextType, ErrorType, ReaderType, WriterType>(
+    reader: &mut ReaderType,
+    writer: &mut WriterType,
+    ctx: &'ctx ContextType,
+) -> Result<Option<PolicyResponse>, PostfixPolicyError<ErrorType>>
+where
+    HandlerType: PolicyRequestHandler<'ctx, ContextType, ErrorType>,
+    ReaderType: BufRead,
+    WriterType: Write,
+{
+    let served = serve_one::<HandlerType, _, _, _, _, _, _>(
+        reader,
+        writer,
+        ctx,
+        &Limits::default(),
+        &mut NoMetrics,
+        &SystemClock,
+        None,
+    )?;
+    match served {
+        Served::Response(response) => Ok(Some(response)),
+        Served::Last(response) => Ok(response),
+    }
+}
+
+/// Configuration for [`handle_connection_with_limits`].
+///
+/// The `Default` implementation gives the behaviour of [`handle_connection`]. Exceeding one of the limits aborts
+/// handling with [`PostfixPolicyError::LimitExceeded`] and the `LimitKind` naming the field.
+///
+/// [`PostfixPolicyError::LimitExceeded`]: enum.PostfixPolicyError.html#variant.LimitExceeded
+/// [`handle_connection_with_limits`]: fn.handle_connection_with_limits.html
+/// [`handle_connection`]: fn.handle_connection.html
+pub struct Limits<ErrorType = ()> {
+    /// If the flush after writing a response fails with the transient error `std::io::ErrorKind::Interrupted`,
+    /// retry it once before returning the error. Any other error is returned immediately.
+    pub retry_interrupted_flush: bool,
+    /// If set, the handler's `attribute` method is called for at most this many attributes per request.
+    /// Any further lines up to the end of the request are read and discarded, then `response` is called
+    /// with whatever was collected. This bounds the work spent on a single request without failing it.
+    pub max_attributes_then_respond: Option<usize>,
+    /// If set, requests containing a `protocol_version` or `policy_protocol` attribute whose value is not in this list
+    /// are aborted with `PostfixPolicyError::UnsupportedProtocolVersion`. Postfix itself doesn't send these
+    /// attributes, but some policy delegation setups do. Requests without them are always accepted.
+    pub accepted_protocol_versions: Option<Vec<Vec<u8>>>,
+    /// If set, the flag is checked between requests (before the first request and after each response was written).
+    /// Once it is `true`, handling stops and `Ok(())` is returned without reading further requests.
+    /// Use this to let connections finish their current request during a graceful shutdown.
+    pub should_stop: Option<Arc<AtomicBool>>,
+    /// If set, a malformed request line doesn't return `PostfixPolicyError::ProtocolError`. Instead this response is
+    /// sent to the server and the connection is closed by returning `Ok(())`.
+    ///
+    /// Be careful what to send here: the request wasn't understood, so anything but a rejection (e.g. `DEFER` with a
+    /// tempfail message) lets the mail pass without the policy having been checked. Also the error is not reported
+    /// to the caller anymore.
+    pub on_protocol_error_response: Option<PolicyResponse>,
+    /// Names of attributes whose values must be valid UTF-8. If one of them isn't, the request is aborted with
+    /// `PostfixPolicyError::InvalidUtf8` before the handler sees the value.
+    pub utf8_attributes: Vec<Vec<u8>>,
+    /// If set, the connection is closed with `LimitKind::MaxConnectionLifetime` once it has been open for longer
+    /// than this. This is checked between requests, so a request in progress is always completed.
+    pub max_connection_lifetime: Option<Duration>,
+    /// If `false`, the handler's `attribute` method isn't called for attributes with an empty value (like
+    /// `client_name=`). Note that this changes the meaning of a missing attribute: the handler can no longer tell
+    /// whether an attribute was sent empty or not sent at all. Defaults to `true`.
+    pub deliver_empty_values: bool,
+    /// If set, called for every attribute before the handler's `attribute` method. If it returns `Err(error)`, the
+    /// request is aborted with `PostfixPolicyError::HandlerError(error)` and the handler never sees the attribute.
+    /// This allows hardening the input for all handlers at one place, e.g. by rejecting control characters.
+    pub validate_attribute: Option<AttributeValidator<ErrorType>>,
+    /// If set, requests with an attribute name longer than this many bytes are aborted with
+    /// `LimitKind::MaxNameLen`. This is checked while the line is read, so it fails as soon as this many bytes
+    /// arrived without a `=`, without buffering the rest of the line. Lines skipped anyway (see
+    /// `skip_comment_lines` and `max_attributes_then_respond`) aren't checked. The names Postfix sends are all
+    /// shorter than 30 bytes.
+    pub max_name_len: Option<usize>,
+    /// If the connection is closed for reading after some lines of a request but before the empty line ending it,
+    /// the request is dropped silently by default. If this is set, the handler's `response` is called anyway and
+    /// its response is sent. This serves servers that shut down their write side but still wait for an answer.
+    /// If the server closed the connection completely, the failure to send the response is ignored.
+    pub respond_on_truncated_request: bool,
+    /// What to do if the handler or [`validate_attribute`] returns an error. Defaults to `OnError::Close`.
+    ///
+    /// [`validate_attribute`]: #structfield.validate_attribute
+    pub on_handler_error: OnError,
+    /// If set, a request has to be received completely, up to the empty line ending it, within this time after its
+    /// first byte arrived. Otherwise handling is aborted with `LimitKind::MaxRequestDuration`. Unlike a read timeout
+    /// on the socket, this also bounds peers sending a request slowly in many small pieces. It is checked each time
+    /// data arrives, so a peer sending nothing at all has to be bounded by a read timeout on the socket.
+    pub max_request_duration: Option<Duration>,
+    /// If set, request lines starting with `#` are skipped instead of being treated as malformed, e.g. a banner or
+    /// comment some clients send before the attributes. They are still passed to the handler's `raw_line`, but not
+    /// to `attribute` and don't count as attributes. No attribute name starts with `#`, so nothing else is skipped:
+    /// any other line without `=` is still a protocol error.
+    pub skip_comment_lines: bool,
+    /// By default, a final line the input ends in without a line feed is used like any other line, e.g.
+    /// `client_address=1.2.3.4` at the end of the input is passed to the handler. If this is set, such a line instead
+    /// aborts handling with `PostfixPolicyError::UnterminatedLine`, as it most likely was cut off.
+    pub require_terminated_lines: bool,
+    /// If set, requests with a line longer than this many bytes (not counting the line feed) are aborted with
+    /// `LimitKind::MaxLineLen`. This is checked while the line is received, so an overlong line is never buffered
+    /// completely.
+    pub max_line_len: Option<usize>,
+    /// If set, requests with more than this many attributes are aborted with `LimitKind::MaxAttributes`. Unlike
+    /// `max_attributes_then_respond`, the request fails instead of being answered.
+    pub max_attributes: Option<usize>,
+    /// If set, a request is aborted with `LimitKind::ReadTimeout` if more than this time passes between two
+    /// pieces of it arriving. The time waited for the first byte of a request isn't bounded, so idle connections stay
+    /// open.
+    ///
+    /// On its own this is a check of the gap when the next data arrives, so it can't interrupt a blocked read.
+    /// [`serve_unix`] additionally sets this duration as read timeout of its sockets, so a peer stalling mid-request
+    /// is cut off as well. Reads failing with `ErrorKind::TimedOut` are taken as such a timeout: within a request
+    /// they fail with `LimitKind::ReadTimeout`, between requests reading is retried unless `should_stop` is set.
+    /// `ErrorKind::WouldBlock` (which Unix sockets report for their own read timeouts and when non-blocking) still
+    /// fails with `PostfixPolicyError::WouldBlock`, so other callers setting a socket timeout get that instead.
+    ///
+    /// [`serve_unix`]: fn.serve_unix.html
+    pub read_timeout: Option<Duration>,
+    /// If set, called with the bytes of every response right before they are written to the socket, e.g. to tee
+    /// them into a capture file. Together with the handler's `raw_line` this gives a transcript of the connection.
+    /// Responses are written as soon as this returns, whether or not the write succeeds afterwards.
+    pub on_bytes_written: Option<ByteTap>,
+    /// How every response is ended. Postfix requires the default `ResponseTerminator::EmptyLine`, the alternative is
+    /// meant for transports that frame the responses themselves.
+    pub response_terminator: ResponseTerminator,
+    /// If set, handling stops after sending a rejection (see `PolicyResponse::is_reject`): instead of waiting for
+    /// another request, anything the peer still sends is read and discarded until it closes the connection, then
+    /// `Ok(())` is returned. Closing with unread data pending makes the kernel reset the connection, which some
+    /// clients handle badly. Bound a peer that never closes with a read timeout on the socket.
+    pub drain_after_reject: bool,
+    /// If set, every response is passed through this before it is sent, including `on_handler_error` and
+    /// `on_protocol_error_response` responses. This applies a policy to the whole connection independent of the
+    /// handler, e.g. turning all rejections into deferrals during a grace period.
+    pub rewrite_response: Option<ResponseRewriter>,
+    /// If set, requests in which more than this many lines are skipped by `skip_comment_lines` or
+    /// `max_attributes_then_respond` are aborted with `LimitKind::MaxSkippedLines`. Otherwise a peer sending
+    /// endless lines that are skipped keeps the request from ever ending. Counted per request.
+    pub max_skipped_lines: Option<usize>,
+    /// If set, called with the name and value of every attribute that passed `validate_attribute`. If it returns
+    /// `Some(value)`, the handler's `attribute` gets that value instead, e.g. to lowercase addresses for all handlers
+    /// at one place. Only returning `Some` allocates, so attributes that stay as they are should return `None`.
+    pub transform_value: Option<ValueTransform>,
+    /// If set, requests with an attribute name containing anything but lowercase letters and `_` are aborted with
+    /// `PostfixPolicyError::InvalidAttributeName`. All names Postfix sends look like this, so this catches peers that
+    /// aren't Postfix early. Off by default, as other clients of the protocol might use different names.
+    pub strict_attribute_names: bool,
+    /// If set, a single `\r` at the end of each value is removed before the value is checked and passed to the
+    /// handler, for proxies that leave one in front of the line feed. Only values are affected: this is no support
+    /// for CRLF line endings, an empty line ending a request still has to be a bare `\n`.
+    pub trim_value_cr: bool,
+}
+
+/// How [`Limits::on_handler_error`] handles errors returned by the handler.
+///
+/// [`Limits::on_handler_error`]: struct.Limits.html#structfield.on_handler_error
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum OnError {
+    /// Stop handling the connection and return `PostfixPolicyError::HandlerError`.
+    #[default]
+    Close,
+    /// Answer the request with this response and continue with the next request. If the error occured while
+    /// passing an attribute, the rest of the request is read but not passed to the handler anymore.
+    ///
+    /// This keeps the connection open through transient failures (e.g. a database that is briefly unreachable),
+    /// but the error itself is dropped and not reported to the caller. Use a temporary rejection like `DEFER`
+    /// here, as the request hasn't really been checked.
+    Respond(PolicyResponse),
+}
+
+/// How [`Limits::response_terminator`] ends the responses.
+///
+/// [`Limits::response_terminator`]: struct.Limits.html#structfield.response_terminator
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ResponseTerminator {
+    /// `action=...\n\n`, the empty line ending the response as the policy protocol requires.
+    #[default]
+    EmptyLine,
+    /// `action=...\n`, only ending the action line.
+    Newline,
+}
+
+impl ResponseTerminator {
+    fn as_bytes(self) -> &'static [u8] {
+        match self {
+            ResponseTerminator::EmptyLine => b"\n\n",
+            ResponseTerminator::Newline => b"\n",
+        }
+    }
+}
+
+/// Callback for [`Limits::validate_attribute`], called with the name and value of each attribute.
+///
+/// [`Limits::validate_attribute`]: struct.Limits.html#structfield.validate_attribute
+pub type AttributeValidator<ErrorType> = Arc<dyn Fn(&[u8], &[u8]) -> Result<(), ErrorType> + Send + Sync>;
+
+/// Callback for [`Limits::on_bytes_written`], called with all bytes written to the socket.
+///
+/// [`Limits::on_bytes_written`]: struct.Limits.html#structfield.on_bytes_written
+pub type ByteTap = Arc<dyn Fn(&[u8]) + Send + Sync>;
+
+/// Callback for [`Limits::rewrite_response`], returning the response to send instead of the given one.
+///
+/// [`Limits::rewrite_response`]: struct.Limits.html#structfield.rewrite_response
+pub type ResponseRewriter = Arc<dyn Fn(PolicyResponse) -> PolicyResponse + Send + Sync>;
+
+/// Callback for [`Limits::transform_value`], called with the name and value of each attribute.
+///
+/// [`Limits::transform_value`]: struct.Limits.html#structfield.transform_value
+pub type ValueTransform = Arc<dyn Fn(&[u8], &[u8]) -> Option<Vec<u8>> + Send + Sync>;
+
+impl<ErrorType> Default for Limits<ErrorType> {
+    fn default() -> Self {
+        Self {
+            retry_interrupted_flush: false,
+            max_attributes_then_respond: None,
+            accepted_protocol_versions: None,
+            should_stop: None,
+            on_protocol_error_response: None,
+            utf8_attributes: Vec::new(),
+            max_connection_lifetime: None,
+            deliver_empty_values: true,
+            validate_attribute: None,
+            max_name_len: None,
+            respond_on_truncated_request: false,
+            on_handler_error: OnError::Close,
+            max_request_duration: None,
+            skip_comment_lines: false,
+            require_terminated_lines: false,
+            max_line_len: None,
+            max_attributes: None,
+            read_timeout: None,
+            on_bytes_written: None,
+            response_terminator: ResponseTerminator::EmptyLine,
+            drain_after_reject: false,
+            rewrite_response: None,
+            max_skipped_lines: None,
+            transform_value: None,
+            strict_attribute_names: false,
+            trim_value_cr: false,
+        }
+    }
+}
+
+// Not derived, as that would require `ErrorType` to implement `Clone` and `Debug` itself.
+impl<ErrorType> Clone for Limits<ErrorType> {
+    fn clone(&self) -> Self {
+        Self {
+            retry_interrupted_flush: self.retry_interrupted_flush,
+            max_attributes_then_respond: self.max_attributes_then_respond,
+            accepted_protocol_versions: self.accepted_protocol_versions.clone(),
+            should_stop: self.should_stop.clone(),
+            on_protocol_error_response: self.on_protocol_error_response.clone(),
+            utf8_attributes: self.utf8_attributes.clone(),
+            max_connection_lifetime: self.max_connection_lifetime,
+            deliver_empty_values: self.deliver_empty_values,
+            validate_attribute: self.validate_attribute.clone(),
+            max_name_len: self.max_name_len,
+            respond_on_truncated_request: self.respond_on_truncated_request,
+            on_handler_error: self.on_handler_error.clone(),
+            max_request_duration: self.max_request_duration,
+            skip_comment_lines: self.skip_comment_lines,
+            require_terminated_lines: self.require_terminated_lines,
+            max_line_len: self.max_line_len,
+            max_attributes: self.max_attributes,
+            read_timeout: self.read_timeout,
+            on_bytes_written: self.on_bytes_written.clone(),
+            response_terminator: self.response_terminator,
+            drain_after_reject: self.drain_after_reject,
+            rewrite_response: self.rewrite_response.clone(),
+            max_skipped_lines: self.max_skipped_lines,
+            transform_value: self.transform_value.clone(),
+            strict_attribute_names: self.strict_attribute_names,
+            trim_value_cr: self.trim_value_cr,
+        }
+    }
+}
+
+impl<ErrorType> std::fmt::Debug for Limits<ErrorType> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Limits")
+            .field("retry_interrupted_flush", &self.retry_interrupted_flush)
+            .field("max_attributes_then_respond", &self.max_attributes_then_respond)
+            .field("accepted_protocol_versions", &self.accepted_protocol_versions)
+            .field("should_stop", &self.should_stop)
+            .field("on_protocol_error_response", &self.on_protocol_error_response)
+            .field("utf8_attributes", &self.utf8_attributes)
+            .field("max_connection_lifetime", &self.max_connection_lifetime)
+            .field("deliver_empty_values", &self.deliver_empty_values)
+            .field("validate_attribute", &self.validate_attribute.as_ref().map(|_| ".."))
+            .field("max_name_len", &self.max_name_len)
+            .field("respond_on_truncated_request", &self.respond_on_truncated_request)
+            .field("on_handler_error", &self.on_handler_error)
+            .field("max_request_duration", &self.max_request_duration)
+            .field("skip_comment_lines", &self.skip_comment_lines)
+            .field("require_terminated_lines", &self.require_terminated_lines)
+            .field("max_line_len", &self.max_line_len)
+            .field("max_attributes", &self.max_attributes)
+            .field("read_timeout", &self.read_timeout)
+            .field("on_bytes_written", &self.on_bytes_written.as_ref().map(|_| ".."))
+            .field("response_terminator", &self.response_terminator)
+            .field("drain_after_reject", &self.drain_after_reject)
+            .field("rewrite_response", &self.rewrite_response.as_ref().map(|_| ".."))
+            .field("max_skipped_lines", &self.max_skipped_lines)
+            .field("transform_value", &self.transform_value.as_ref().map(|_| ".."))
+            .field("strict_attribute_names", &self.strict_attribute_names)
+            .field("trim_value_cr", &self.trim_value_cr)
+            .finish()
+    }
+}
+
+impl<ErrorType> Limits<ErrorType> {
+    /// Returns a [`LimitsBuilder`] starting from the defaults, as an alternative to filling in the fields directly.
+    ///
+    /// ```norun
+    /// let limits = Limits::builder()
+    ///     .max_line_len(1024)
+    ///     .max_attributes(100)
+    ///     .read_timeout(Duration::from_secs(10))
+    ///     .build();
+    /// ```
+    ///
+    /// [`LimitsBuilder`]: struct.LimitsBuilder.html
+    pub fn builder() -> LimitsBuilder<ErrorType> {
+        LimitsBuilder {
+            limits: Limits::default(),
+        }
+    }
+}
+
+/// Builds [`Limits`] one setting at a time. Every method sets the field of the same name, see there for details.
+///
+/// [`Limits`]: struct.Limits.html
+pub struct LimitsBuilder<ErrorType = ()> {
+    limits: Limits<ErrorType>,
+}
+
+impl<ErrorType> LimitsBuilder<ErrorType> {
+    pub fn retry_interrupted_flush(mut self, retry: bool) -> Self {
+        self.limits.retry_interrupted_flush = retry;
+        self
+    }
+
+    pub fn max_attributes_then_respond(mut self, max: usize) -> Self {
+        self.limits.max_attributes_then_respond = Some(max);
+        self
+    }
+
+    pub fn accepted_protocol_versions(mut self, versions: Vec<Vec<u8>>) -> Self {
+        self.limits.accepted_protocol_versions = Some(versions);
+        self
+    }
+
+    pub fn should_stop(mut self, should_stop: Arc<AtomicBool>) -> Self {
+        self.limits.should_stop = Some(should_stop);
+        self
+    }
+
+    pub fn on_protocol_error_response(mut self, response: PolicyResponse) -> Self {
+        self.limits.on_protocol_error_response = Some(response);
+        self
+    }
+
+    pub fn utf8_attributes(mut self, names: Vec<Vec<u8>>) -> Self {
+        self.limits.utf8_attributes = names;
+        self
+    }
+
+    pub fn max_connection_lifetime(mut self, lifetime: Duration) -> Self {
+        self.limits.max_connection_lifetime = Some(lifetime);
+        self
+    }
+
+    pub fn deliver_empty_values(mut self, deliver: bool) -> Self {
+        self.limits.deliver_empty_values = deliver;
+        self
+    }
+
+    pub fn validate_attribute<F>(mut self, validate: F) -> Self
+    where
+        F: Fn(&[u8], &[u8]) -> Result<(), ErrorType> + Send + Sync + 'static,
+    {
+        self.limits.validate_attribute = Some(Arc::new(validate));
+        self
+    }
+
+    pub fn max_name_len(mut self, max: usize) -> Self {
+        self.limits.max_name_len = Some(max);
+        self
+    }
+
+    pub fn respond_on_truncated_request(mut self, respond: bool) -> Self {
+        self.limits.respond_on_truncated_request = respond;
+        self
+    }
+
+    pub fn on_handler_error(mut self, on_error: OnError) -> Self {
+        self.limits.on_handler_error = on_error;
+        self
+    }
+
+    pub fn max_request_duration(mut self, duration: Duration) -> Self {
+        self.limits.max_request_duration = Some(duration);
+        self
+    }
+
+    pub fn skip_comment_lines(mut self, skip: bool) -> Self {
+        self.limits.skip_comment_lines = skip;
+        self
+    }
+
+    pub fn require_terminated_lines(mut self, require: bool) -> Self {
+        self.limits.require_terminated_lines = require;
+        self
+    }
+
+    pub fn max_line_len(mut self, max: usize) -> Self {
+        self.limits.max_line_len = Some(max);
+        self
+    }
+
+    pub fn max_attributes(mut self, max: usize) -> Self {
+        self.limits.max_attributes = Some(max);
+        self
+    }
+
+    /// Sets `Limits::read_timeout`. Unless the socket has a read timeout as well (which [`serve_unix`] sets), this is
+    /// only a check of the gap between arriving data, see the field.
+    ///
+    /// [`serve_unix`]: fn.serve_unix.html
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.limits.read_timeout = Some(timeout);
+        self
+    }
+
+    pub fn on_bytes_written<F>(mut self, tap: F) -> Self
+    where
+        F: Fn(&[u8]) + Send + Sync + 'static,
+    {
+        self.limits.on_bytes_written = Some(Arc::new(tap));
+        self
+    }
+
+    pub fn response_terminator(mut self, terminator: ResponseTerminator) -> Self {
+        self.limits.response_terminator = terminator;
+        self
+    }
+
+    pub fn drain_after_reject(mut self, drain: bool) -> Self {
+        self.limits.drain_after_reject = drain;
+        self
+    }
+
+    pub fn rewrite_response<F>(mut self, rewrite: F) -> Self
+    where
+        F: Fn(PolicyResponse) -> PolicyResponse + Send + Sync + 'static,
+    {
+        self.limits.rewrite_response = Some(Arc::new(rewrite));
+        self
+    }
+
+    pub fn max_skipped_lines(mut self, max: usize) -> Self {
+        self.limits.max_skipped_lines = Some(max);
+        self
+    }
+
+    pub fn transform_value<F>(mut self, transform: F) -> Self
+    where
+        F: Fn(&[u8], &[u8]) -> Option<Vec<u8>> + Send + Sync + 'static,
+    {
+        self.limits.transform_value = Some(Arc::new(transform));
+        self
+    }
+
+    pub fn strict_attribute_names(mut self, strict: bool) -> Self {
+        self.limits.strict_attribute_names = strict;
+        self
+    }
+
+    pub fn trim_value_cr(mut self, trim: bool) -> Self {
+        self.limits.trim_value_cr = trim;
+        self
+    }
+
+    /// Returns the configured `Limits`.
+    pub fn build(self) -> Limits<ErrorType> {
+        self.limits
+    }
+}
+
+/// Handles a connection to the mail server like [`handle_connection`], configured by `limits`.
+///
+/// [`handle_connection`]: fn.handle_connection.html
+pub fn handle_connection_with_limits<'socket, 'ctx, HandlerType, ContextType, ErrorType, SocketType>(
+    socket: &'socket SocketType,
+    ctx: &'ctx ContextType,
+    limits: &Limits<ErrorType>,
+) -> Result<(), PostfixPolicyError<ErrorType>>
+where
+    HandlerType: PolicyRequestHandler<'ctx, ContextType, ErrorType>,
+    &'socket SocketType: Read + Write,
+{
+    handle_connection_with_metrics::<HandlerType, ContextType, ErrorType, SocketType, _, _>(
+        socket,
+        ctx,
+        limits,
+        &mut NoMetrics,
+        &SystemClock,
+    )
+}
+
+/// Handles a connection to the mail server like [`handle_connection_with_limits`], reporting measurements to `metrics`.
+///
+/// All times are taken from `clock`.
+///
+/// [`handle_connection_with_limits`]: fn.handle_connection_with_limits.html
+pub fn handle_connection_with_metrics<
+    'socket,
+    'ctx,
+    HandlerType,
+    ContextType,
+    ErrorType,
+    SocketType,
+    MetricsType,
+    ClockType,
+>(
+    mut socket: &'socket SocketType,
+    ctx: &'ctx ContextType,
+    limits: &Limits<ErrorType>,
+    metrics: &mut MetricsType,
+    clock: &ClockType,
+) -> Result<(), PostfixPolicyError<ErrorType>>
+where
+    HandlerType: PolicyRequestHandler<'ctx, ContextType, ErrorType>,
+    &'socket SocketType: Read + Write,
+    MetricsType: Metrics,
+    ClockType: Clock,
+{
+    let mut reader = BufReader::new(socket);
+    serve_requests::<HandlerType, _, _, _, _, _, _>(&mut reader, &mut socket, ctx, limits, metrics, clock, None)
+}
+
+/// Handles a connection to the mail server like [`handle_connection`], additionally returning all bytes that were
+/// received but weren't part of a complete request.
+///
+/// These are the lines of a partial trailing request, any data still buffered when an error occured and an
+/// unterminated final line. This helps diagnosing framing mismatches with misbehaving peers.
+///
+/// [`handle_connection`]: fn.handle_connection.html
+pub fn handle_connection_with_leftover<'socket, 'ctx, HandlerType, ContextType, ErrorType, SocketType>(
+    mut socket: &'socket SocketType,
+    ctx: &'ctx ContextType,
+) -> (Result<(), PostfixPolicyError<ErrorType>>, Vec<u8>)
+where
+    HandlerType: PolicyRequestHandler<'ctx, ContextType, ErrorType>,
+    &'socket SocketType: Read + Write,
+{
+    let mut reader = BufReader::new(socket);
+    let mut leftover = Vec::new();
+    let result = serve_requests::<HandlerType, _, _, _, _, _, _>(
+        &mut reader,
+        &mut socket,
+        ctx,
+        &Limits::default(),
+        &mut NoMetrics,
+        &SystemClock,
+        Some(&mut leftover),
+    );
+    leftover.extend_from_slice(reader.buffer());
+    (result, leftover)
+}
+
+/// Handles a connection to the mail server like [`handle_connection_with_limits`], additionally returning all bytes
+/// written to `socket`, i.e. the serialized responses (including those to protocol errors).
+///
+/// This allows e.g. a proxy to act on the responses locally and forward the exact bytes elsewhere. The bytes are
+/// returned even if handling failed, up to the error. [`Limits::on_bytes_written`] still sees them as well.
+///
+/// [`handle_connection_with_limits`]: fn.handle_connection_with_limits.html
+/// [`Limits::on_bytes_written`]: struct.Limits.html#structfield.on_bytes_written
+pub fn handle_connection_with_response_bytes<'socket, 'ctx, HandlerType, ContextType, ErrorType, SocketType>(
+    socket: &'socket SocketType,
+    ctx: &'ctx ContextType,
+    limits: &Limits<ErrorType>,
+) -> (Result<(), PostfixPolicyError<ErrorType>>, Vec<u8>)
+where
+    HandlerType: PolicyRequestHandler<'ctx, ContextType, ErrorType>,
+    &'socket SocketType: Read + Write,
+{
+    let mut reader = BufReader::new(socket);
+    let mut writer = CopyingWriter {
+        inner: socket,
+        copy: Vec::new(),
+    };
+    let result = serve_requests::<HandlerType, _, _, _, _, _, _>(
+        &mut reader,
+        &mut writer,
+        ctx,
+        limits,
+        &mut NoMetrics,
+        &SystemClock,
+        None,
+    );
+    (result, writer.copy)
+}
+
+/// Writes to `inner`, keeping a copy of everything `inner` accepted.
+struct CopyingWriter<W> {
+    inner: W,
+    copy: Vec<u8>,
+}
+
+impl<W: Write> Write for CopyingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.copy.extend_from_slice(&buf[..written]);
+        Ok(written)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> std::io::Result<usize> {
+        let written = self.inner.write_vectored(bufs)?;
+        let mut remaining = written;
+        for buf in bufs {
+            let len = buf.len().min(remaining);
+            self.copy.extend_from_slice(&buf[..len]);
+            remaining -= len;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The request loop behind all `handle_connection` variants. If `pending` is given, it receives the bytes of the
+/// current incomplete request.
+fn serve_requests<'ctx, HandlerType, ContextType, ErrorType, ReaderType, WriterType, MetricsType, ClockType>(
+    reader: &mut ReaderType,
+    writer: &mut WriterType,
+    ctx: &'ctx ContextType,
+    limits: &Limits<ErrorType>,
+    metrics: &mut MetricsType,
+    clock: &ClockType,
+    pending: Option<&mut Vec<u8>>,
+) -> Result<(), PostfixPolicyError<ErrorType>>
+where
+    HandlerType: PolicyRequestHandler<'ctx, ContextType, ErrorType>,
+    ReaderType: BufRead,
+    WriterType: Write,
+    MetricsType: Metrics,
+    ClockType: Clock,
+{
+    HandlerType::on_connection_start(ctx);
+    let result =
+        serve_request_loop::<HandlerType, _, _, _, _, _, _>(reader, writer, ctx, limits, metrics, clock, pending);
+    HandlerType::on_connection_end(ctx, &result);
+    result
+}
+
+fn serve_request_loop<'ctx, HandlerType, ContextType, ErrorType, ReaderType, WriterType, MetricsType, ClockType>(
+    reader: &mut ReaderType,
+    writer: &mut WriterType,
+    ctx: &'ctx ContextType,
+    limits: &Limits<ErrorType>,
+    metrics: &mut MetricsType,
+    clock: &ClockType,
+    mut pending: Option<&mut Vec<u8>>,
+) -> Result<(), PostfixPolicyError<ErrorType>>
+where
+    HandlerType: PolicyRequestHandler<'ctx, ContextType, ErrorType>,
+    ReaderType: BufRead,
+    WriterType: Write,
+    MetricsType: Metrics,
+    ClockType: Clock,
+{
+    let connection_start = clock.now();
+
+    loop {
+        if let Some(should_stop) = &limits.should_stop {
+            if should_stop.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+        }
+        if let Some(max_lifetime) = limits.max_connection_lifetime {
+            if clock.now() - connection_start > max_lifetime {
+                return Err(PostfixPolicyError::LimitExceeded {
+                    limit: LimitKind::MaxConnectionLifetime,
+                });
+            }
+        }
+        let served = serve_one::<HandlerType, _, _, _, _, _, _>(
+            reader,
+            writer,
+            ctx,
+            limits,
+            metrics,
+            clock,
+            pending.as_deref_mut(),
+        )?;
+        if let Served::Last(_) = served {
+            return Ok(());
+        }
+    }
+}
+
+/// What [`serve_one`] did with a request.
+enum Served {
+    /// The response was sent and more requests may follow.
+    Response(PolicyResponse),
+    /// The connection is done, after sending the response if there is one.
+    Last(Option<PolicyResponse>),
+}
+
+/// Reads, handles and answers a single request: the body of the request loop, shared with `handle_one`.
+fn serve_one<'ctx, HandlerType, ContextType, ErrorType, ReaderType, WriterType, MetricsType, ClockType>(
+    reader: &mut ReaderType,
+    writer: &mut WriterType,
+    ctx: &'ctx ContextType,
+    limits: &Limits<ErrorType>,
+    metrics: &mut MetricsType,
+    clock: &ClockType,
+    mut pending: Option<&mut Vec<u8>>,
+) -> Result<Served, PostfixPolicyError<ErrorType>>
+where
+    HandlerType: PolicyRequestHandler<'ctx, ContextType, ErrorType>,
+    ReaderType: BufRead,
+    WriterType: Write,
+    MetricsType: Metrics,
+    ClockType: Clock,
+{
+    if let Some(pending) = pending.as_mut() {
+        pending.clear();
+    }
+
+    let mut handler: HandlerType = HandlerType::new(ctx);
+    let (request, truncated) = match read_request(reader, &mut handler, limits, clock, pending)? {
+        RequestEnd::Complete(request) => (request, false),
+        RequestEnd::Eof => return Ok(Served::Last(None)),
+        RequestEnd::Truncated(_) if !limits.respond_on_truncated_request => return Ok(Served::Last(None)),
+        RequestEnd::Truncated(request) => (request, true),
+        RequestEnd::Malformed(line) => return protocol_error(writer, line, limits).map(|r| Served::Last(Some(r))),
+    };
+    let read_end = clock.now();
+    metrics.on_request_parsed(request.size, request.attribute_count);
+    let result = match request.error {
+        Some(error) => Err(error),
+        None => handler.response(),
+    };
+    let compute_end = clock.now();
+    let response = match (result, &limits.on_handler_error) {
+        (Ok(response), _) => response,
+        (Err(_), OnError::Respond(fallback)) => fallback.clone(),
+        (Err(error), OnError::Close) => return Err(PostfixPolicyError::HandlerError(error)),
+    };
+    let response = match &limits.rewrite_response {
+        Some(rewrite) => rewrite(response),
+        None => response,
+    };
+    let drain = limits.drain_after_reject && response.is_reject();
+    match write_response(writer, response.clone(), limits) {
+        // if the server closed the connection completely, there is nobody left to tell
+        Err(PostfixPolicyError::IoError(ref e))
+            if truncated && (e.kind() == ErrorKind::BrokenPipe || e.kind() == ErrorKind::ConnectionReset) =>
+        {
+            return Ok(Served::Last(None))
+        }
+        result => result?,
+    }
+    let end = clock.now();
+    metrics.on_request_complete(&request.protocol_state, end - request.start);
+    let timings = RequestTimings {
+        read: read_end - request.start,
+        compute: compute_end - read_end,
+        write: end - compute_end,
+    };
+    metrics.on_request_timings(&request.protocol_state, &timings);
+    if drain {
+        discard_input(reader)?;
+        return Ok(Served::Last(Some(response)));
+    }
+    if truncated {
+        return Ok(Served::Last(Some(response)));
+    }
+    Ok(Served::Response(response))
+}
+
+/// Reads and discards everything from `reader` until EOF, for `Limits::drain_after_reject`.
+fn discard_input<ReaderType: BufRead, ErrorType>(reader: &mut ReaderType) -> Result<(), PostfixPolicyError<ErrorType>> {
+    loop {
+        let len = match reader.fill_buf() {
+            Ok(available) => available.len(),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if len == 0 {
+            return Ok(());
+        }
+        reader.consume(len);
+    }
+}
+
+/// How reading a request ended.
+enum RequestEnd<ErrorType> {
+    /// The empty line ending the request was read.
+    Complete(ReadRequest<ErrorType>),
+    /// The input ended before the first line of a request.
+    Eof,
+    /// The input ended after some lines of the request, but before its empty line.
+    Truncated(ReadRequest<ErrorType>),
+    /// This line isn't a valid attribute.
+    Malformed(Vec<u8>),
+}
+
+/// What `read_request` kept about a request whose attributes were passed to the handler.
+struct ReadRequest<ErrorType> {
+    /// When the first byte of the request was received.
+    start: Instant,
+    /// Value of the `protocol_state` attribute, empty if there was none.
+    protocol_state: Vec<u8>,
+    /// The handler error that occured while passing the attributes, if it is answered with
+    /// `OnError::Respond`. The remaining attributes weren't passed to the handler then.
+    error: Option<ErrorType>,
+    /// Number of bytes received for the request.
+    size: usize,
+    /// Number of attributes, as counted for `Limits::max_attributes`.
+    attribute_count: usize,
+}
+
+/// Reads one request, passing its attributes to `handler`.
+fn read_request<'ctx, HandlerType, ContextType, ErrorType, ReaderType, ClockType>(
+    reader: &mut ReaderType,
+    handler: &mut HandlerType,
+    limits: &Limits<ErrorType>,
+    clock: &ClockType,
+    mut pending: Option<&mut Vec<u8>>,
+) -> Result<RequestEnd<ErrorType>, PostfixPolicyError<ErrorType>>
+where
+    HandlerType: PolicyRequestHandler<'ctx, ContextType, ErrorType>,
+    ReaderType: BufRead,
+    ClockType: Clock,
+{
+    let mut attribute_count: usize = 0;
+    let mut skipped_lines: usize = 0;
+    let mut size: usize = 0;
+    let mut start = None;
+    let mut last_data = None;
+    let mut protocol_state = Vec::new();
+    let mut error = None;
+
+    loop {
+        let mut buf: Vec<u8> = vec![];
+        let skipping = error.is_some()
+            || limits
+                .max_attributes_then_respond
+                .is_some_and(|max| attribute_count >= max);
+        let max_name_len = limits.max_name_len.filter(|_| !skipping);
+        if read_line(
+            reader,
+            &mut buf,
+            &mut start,
+            &mut last_data,
+            max_name_len,
+            limits,
+            clock,
+        )? == 0
+        {
+            return Ok(match start {
+                None => RequestEnd::Eof,
+                Some(start) => RequestEnd::Truncated(ReadRequest {
+                    start,
+                    protocol_state,
+                    error,
+                    size,
+                    attribute_count,
+                }),
+            });
+        }
+        let start = start.expect("read_line sets start when receiving data");
+        size += buf.len();
+        if let Some(pending) = pending.as_mut() {
+            pending.extend_from_slice(&buf);
+        }
+        handler.raw_line(&buf);
+        if limits.require_terminated_lines && !buf.ends_with(b"\n") {
+            return Err(PostfixPolicyError::UnterminatedLine(buf));
+        }
+
+        if buf == b"\n" {
+            return Ok(RequestEnd::Complete(ReadRequest {
+                start,
+                protocol_state,
+                error,
+                size,
+                attribute_count,
+            }));
+        }
+        if error.is_some() {
+            continue;
+        }
+        let skip = (limits.skip_comment_lines && buf.starts_with(b"#"))
+            || limits
+                .max_attributes_then_respond
+                .is_some_and(|max| attribute_count >= max);
+        if skip {
+            skipped_lines += 1;
+            if limits.max_skipped_lines.is_some_and(|max| skipped_lines > max) {
+                return Err(PostfixPolicyError::LimitExceeded {
+                    limit: LimitKind::MaxSkippedLines,
+                });
+            }
+            continue;
+        }
+        attribute_count += 1;
+        if limits.max_attributes.is_some_and(|max| attribute_count > max) {
+            return Err(PostfixPolicyError::LimitExceeded {
+                limit: LimitKind::MaxAttributes,
+            });
+        }
+
+        // the final line before EOF might not be terminated
+        let line = buf.strip_suffix(b"\n").unwrap_or(&buf);
+        let pos = match line.iter().position(|&c| c == b'=') {
+            Some(pos) if pos > 0 => pos,
+            _ => return Ok(RequestEnd::Malformed(buf)),
+        };
+        let (left, right) = (&line[..pos], &line[pos + 1..]);
+        if limits.strict_attribute_names && !left.iter().all(|&c| c.is_ascii_lowercase() || c == b'_') {
+            return Err(PostfixPolicyError::InvalidAttributeName(left.to_vec()));
+        }
+        let right = match right {
+            [value @ .., b'\r'] if limits.trim_value_cr => value,
+            _ => right,
+        };
+        if left == b"protocol_state" {
+            protocol_state = right.to_vec();
+        }
+        if limits.utf8_attributes.iter().any(|name| name == left) && std::str::from_utf8(right).is_err() {
+            return Err(PostfixPolicyError::InvalidUtf8 { name: left.to_vec() });
+        }
+        if let Some(accepted) = &limits.accepted_protocol_versions {
+            if (left == b"protocol_version" || left == b"policy_protocol")
+                && !accepted.iter().any(|version| version == right)
+            {
+                return Err(PostfixPolicyError::UnsupportedProtocolVersion(right.to_vec()));
+            }
+        }
+        let result = match &limits.validate_attribute {
+            Some(validate) => validate(left, right),
+            None => Ok(()),
+        };
+        let transformed = match &limits.transform_value {
+            Some(transform) if result.is_ok() => transform(left, right),
+            _ => None,
+        };
+        let right = transformed.as_deref().unwrap_or(right);
+        let result = match result {
+            Ok(()) if right.is_empty() && !limits.deliver_empty_values => continue,
+            Ok(()) => handler.attribute(left, right).map_or(Ok(()), Err),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            if let OnError::Close = limits.on_handler_error {
+                return Err(PostfixPolicyError::HandlerError(e));
+            }
+            error = Some(e);
+        }
+    }
+}
+
+/// Reads a line into `buf` like `BufRead::read_until`, returning the number of bytes read.
+///
+/// `start` is set when the first byte arrives if it isn't set yet, `last_data` every time data arrives. Then the
+/// request is checked against `Limits::max_request_duration` and `Limits::read_timeout`, and the line against
+/// `Limits::max_line_len` and `max_name_len`, which is why the reading can't be left to `read_until`.
+/// `max_name_len` is passed separately, as it doesn't apply to lines that are skipped.
+fn read_line<ReaderType, ErrorType, ClockType>(
+    reader: &mut ReaderType,
+    buf: &mut Vec<u8>,
+    start: &mut Option<Instant>,
+    last_data: &mut Option<Instant>,
+    max_name_len: Option<usize>,
+    limits: &Limits<ErrorType>,
+    clock: &ClockType,
+) -> Result<usize, PostfixPolicyError<ErrorType>>
+where
+    ReaderType: BufRead,
+    ClockType: Clock,
+{
+    let mut read = 0;
+    loop {
+        let available = match reader.fill_buf() {
+            Ok(available) => available,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            // a socket read timeout set for `read_timeout`, which only applies within a request
+            Err(e) if limits.read_timeout.is_some() && e.kind() == ErrorKind::TimedOut => {
+                if start.is_none() {
+                    // read is 0 here, which ends the connection like EOF
+                    if limits
+                        .should_stop
+                        .as_ref()
+                        .is_some_and(|stop| stop.load(Ordering::SeqCst))
+                    {
+                        return Ok(read);
+                    }
+                    continue;
+                }
+                return Err(PostfixPolicyError::LimitExceeded {
+                    limit: LimitKind::ReadTimeout,
+                });
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return Err(PostfixPolicyError::WouldBlock),
+            Err(e) => return Err(e.into()),
+        };
+        if available.is_empty() {
+            return Ok(read);
+        }
+        let now = clock.now();
+        let request_start = *start.get_or_insert(now);
+        if let Some(max_duration) = limits.max_request_duration {
+            if now - request_start > max_duration {
+                return Err(PostfixPolicyError::LimitExceeded {
+                    limit: LimitKind::MaxRequestDuration,
+                });
+            }
+        }
+        if let (Some(timeout), Some(last_data)) = (limits.read_timeout, *last_data) {
+            if now - last_data > timeout {
+                return Err(PostfixPolicyError::LimitExceeded {
+                    limit: LimitKind::ReadTimeout,
+                });
+            }
+        }
+        *last_data = Some(now);
+        let (complete, used) = match available.iter().position(|&c| c == b'\n') {
+            Some(pos) => (true, pos + 1),
+            None => (false, available.len()),
+        };
+        if let Some(max) = limits.max_line_len {
+            if buf.len() + used - complete as usize > max {
+                return Err(PostfixPolicyError::LimitExceeded {
+                    limit: LimitKind::MaxLineLen,
+                });
+            }
+        }
+        if let Some(max) = max_name_len {
+            let line_len = buf.len() + used - complete as usize;
+            let comment = limits.skip_comment_lines && buf.first().or(available.first()) == Some(&b'#');
+            // the line has more than `max` bytes, so its first `max + 1` bytes have to contain the `=`
+            let seen = buf.len().min(max + 1);
+            if line_len > max
+                && !comment
+                && !buf[..seen].contains(&b'=')
+                && !available[..max + 1 - seen].contains(&b'=')
+            {
+                return Err(PostfixPolicyError::LimitExceeded {
+                    limit: LimitKind::MaxNameLen,
+                });
+            }
+        }
+        buf.extend_from_slice(&available[..used]);
+        reader.consume(used);
+        read += used;
+        if complete {
+            return Ok(read);
+        }
+    }
+}
+
+/// Without the `test-helpers` feature, `test_helper` isn't part of the library:
+#[cfg_attr(not(feature = "test-helpers"), doc = "```compile_fail")]
+#[cfg_attr(feature = "test-helpers", doc = "```")]
+/// use postfix_policy::test_helper::DummySocket;
+/// ```
+#[allow(dead_code)]
+struct TestHelperFeatureCheck;
+
+/**
+ Handles a single request given as a complete buffer, without doing any IO.
+
+ `request` has to contain exactly one request including the empty line ending it. A new handler is created with
+ `ctx`, gets passed the attributes and its response is returned instead of being sent anywhere. This allows
+ integrating the handler into event loops that do the IO themselves.
+ Incomplete requests and trailing data after the request are reported as `PostfixPolicyError::ProtocolError`.
+*/
+pub fn process_request<'ctx, HandlerType, ContextType, ErrorType>(
+    request: &[u8],
+    ctx: &'ctx ContextType,
+) -> Result<PolicyResponse, PostfixPolicyError<ErrorType>>
+where
+    HandlerType: PolicyRequestHandler<'ctx, ContextType, ErrorType>,
+{
+    let mut reader = request;
+    let mut handler: HandlerType = HandlerType::new(ctx);
+    match read_request(&mut reader, &mut handler, &Limits::default(), &SystemClock, None)? {
+        RequestEnd::Complete(_) if reader.is_empty() => handler.response().map_err(PostfixPolicyError::HandlerError),
+        RequestEnd::Complete(_) => Err(PostfixPolicyError::ProtocolError(reader.to_vec())),
+        RequestEnd::Eof | RequestEnd::Truncated(_) => Err(PostfixPolicyError::ProtocolError(request.to_vec())),
+        RequestEnd::Malformed(line) => Err(PostfixPolicyError::ProtocolError(line)),
+    }
+}
+
+/**
+ Handles a single request delivered as one chunk by an outer framing, e.g. a length-prefixed binary protocol the
+ policy protocol is tunneled through.
+
+ Works like [`process_request`], but as the framing already tells where the request ends, the empty line ending it
+ (and the line feed after the last attribute) may be left out of `chunk`. An empty chunk is an empty request.
+ Framing, reading the chunk and sending the response are left to the caller; [`flush_response`] can write the
+ response into a buffer to frame.
+
+ [`process_request`]: fn.process_request.html
+ [`flush_response`]: fn.flush_response.html
+*/
+pub fn handle_framed_request<'ctx, HandlerType, ContextType, ErrorType>(
+    chunk: &[u8],
+    ctx: &'ctx ContextType,
+) -> Result<PolicyResponse, PostfixPolicyError<ErrorType>>
+where
+    HandlerType: PolicyRequestHandler<'ctx, ContextType, ErrorType>,
+{
+    if chunk == b"\n" || chunk.ends_with(b"\n\n") {
+        return process_request::<HandlerType, _, _>(chunk, ctx);
+    }
+    let mut request = chunk.to_vec();
+    if !request.is_empty() && !request.ends_with(b"\n") {
+        request.push(b'\n');
+    }
+    request.push(b'\n');
+    process_request::<HandlerType, _, _>(&request, ctx)
+}
+
+/// Sends `response` to the server over `writer` and flushes it.
+///
+/// Only one action can be sent per request. The `action=` prefix, the action and the empty line ending the
+/// response are passed to `writer` together using `write_vectored`, so an unbuffered socket sees a single write
+/// without the response being copied into another buffer first. Writers without vectored IO write the parts one after
+/// another. This is what all `handle_connection` variants use to answer a request.
+pub fn flush_response<W: Write, ErrorType>(
+    writer: &mut W,
+    response: PolicyResponse,
+) -> Result<(), PostfixPolicyError<ErrorType>> {
+    let action = serialize_response(response).map_err(PostfixPolicyError::InvalidResponse)?;
+    write_framed(writer, &action, ResponseTerminator::EmptyLine)?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn write_response<W: Write, ErrorType>(
+    writer: &mut W,
+    response: PolicyResponse,
+    limits: &Limits<ErrorType>,
+) -> Result<(), PostfixPolicyError<ErrorType>> {
+    let action = serialize_response(response).map_err(PostfixPolicyError::InvalidResponse)?;
+    if let Some(tap) = &limits.on_bytes_written {
+        tap(&[&b"action="[..], &action, limits.response_terminator.as_bytes()].concat());
+    }
+    write_framed(writer, &action, limits.response_terminator)?;
+    flush(writer, limits)?;
+    Ok(())
+}
+
+/// Writes `action=`, `action` and the `terminator` like `write_all` would write them concatenated.
+fn write_framed<W: Write>(writer: &mut W, action: &[u8], terminator: ResponseTerminator) -> std::io::Result<()> {
+    let mut parts = [
+        IoSlice::new(b"action="),
+        IoSlice::new(action),
+        IoSlice::new(terminator.as_bytes()),
+    ];
+    let mut parts = &mut parts[..];
+    while !parts.is_empty() {
+        match writer.write_vectored(parts) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(written) => IoSlice::advance_slices(&mut parts, written),
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn protocol_error<W: Write, ErrorType>(
+    writer: &mut W,
+    line: Vec<u8>,
+    limits: &Limits<ErrorType>,
+) -> Result<PolicyResponse, PostfixPolicyError<ErrorType>> {
+    match &limits.on_protocol_error_response {
+        Some(response) => {
+            let response = match &limits.rewrite_response {
+                Some(rewrite) => rewrite(response.clone()),
+                None => response.clone(),
+            };
+            write_response(writer, response.clone(), limits)?;
+            Ok(response)
+        }
+        None => Err(PostfixPolicyError::ProtocolError(line)),
+    }
+}
+
+fn flush<W: Write, ErrorType>(writer: &mut W, limits: &Limits<ErrorType>) -> std::io::Result<()> {
+    match writer.flush() {
+        Err(ref e) if limits.retry_interrupted_flush && e.kind() == std::io::ErrorKind::Interrupted => writer.flush(),
+        result => result,
+    }
+}
+
+/// provides helpers for testing. \
+/// Only available with the `test-helpers` feature, so production builds don't include it. Enable it in the
+/// `[dev-dependencies]` of crates testing their handlers with it.
+#[cfg(any(test, feature = "test-helpers"))]
+pub mod test_helper {
+    use super::{
+        handle_connection, handle_connection_split, Clock, PolicyRequestHandler, PolicyResponse, PostfixPolicyError,
+    };
+    use std::cell::{Cell, RefCell};
+    use std::collections::VecDeque;
+    use std::fs::File;
+    use std::io::Cursor;
+    use std::io::{BufRead, BufReader, ErrorKind, IoSlice, Read, Write};
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    /// A [`Clock`] that only advances when told to, for testing time dependent behaviour.
+    ///
+    /// [`Clock`]: ../trait.Clock.html
+    pub struct FakeClock {
+        start: Instant,
+        elapsed: Mutex<Duration>,
+    }
+
+    impl FakeClock {
+        /// creates a new `FakeClock` instance, starting at the current time.
+        pub fn new() -> Self {
+            FakeClock {
+                start: Instant::now(),
+                elapsed: Mutex::new(Duration::from_secs(0)),
+            }
+        }
+
+        /// moves the time returned by `now` forward by `duration`.
+        pub fn advance(&self, duration: Duration) {
+            *self.elapsed.lock().unwrap() += duration;
+        }
+    }
+
+    impl Default for FakeClock {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            self.start + *self.elapsed.lock().unwrap()
+        }
+    }
+
+    /// A Dummy Socket, implementing `Read` and `Write`. It is give an `&[u8]` input which will be returned by `read` calls. After using it, the complete written output can be obtained by calling `get_output`.
+    pub struct DummySocket<'lt> {
+        input: RefCell<Cursor<&'lt [u8]>>,
+        output: RefCell<Vec<u8>>,
+        flush_errors: RefCell<VecDeque<ErrorKind>>,
+        read_errors: RefCell<VecDeque<ErrorKind>>,
+        slow_reads: Cell<Option<(usize, &'lt FakeClock, Duration)>>,
+        slow_writes: Cell<Option<(&'lt FakeClock, Duration)>>,
+    }
+
+    impl<'lt> DummySocket<'lt> {
+        /// creates a new `DummySocket` instance. The data given as `input` can be read by calling `read`.
+        pub fn new(input: &'lt [u8]) -> Self {
+            DummySocket {
+                input: RefCell::new(Cursor::new(input)),
+                output: RefCell::new(vec![]),
+                flush_errors: RefCell::new(VecDeque::new()),
+                read_errors: RefCell::new(VecDeque::new()),
+                slow_reads: Cell::new(None),
+                slow_writes: Cell::new(None),
+            }
+        }
+
+        /// makes a future call to `flush` fail with an error of the given `kind`. Queued errors are returned by consecutive `flush` calls in the order they were added.
+        pub fn fail_flush(&self, kind: ErrorKind) {
+            self.flush_errors.borrow_mut().push_back(kind);
+        }
+
+        /// makes a future call to `read` fail with an error of the given `kind`. Queued errors are returned by consecutive `read` calls in the order they were added, before any further input.
+        pub fn fail_read(&self, kind: ErrorKind) {
+            self.read_errors.borrow_mut().push_back(kind);
+        }
+
+        /// makes every `read` return at most `max_len` bytes and advance `clock` by `delay` first, simulating a peer sending its data slowly.
+        pub fn slow_reads(&self, max_len: usize, clock: &'lt FakeClock, delay: Duration) {
+            self.slow_reads.set(Some((max_len, clock, delay)));
+        }
+
+        /// makes every `write` advance `clock` by `delay`, simulating a slow peer.
+        pub fn slow_writes(&self, clock: &'lt FakeClock, delay: Duration) {
+            self.slow_writes.set(Some((clock, delay)));
+        }
+
+        /// returns the output written into this `DummySocket`.
         pub fn get_output(self) -> Vec<u8> {
             self.output.into_inner()
         }
     }
 
-    impl<'lt> Read for &DummySocket<'lt> {
-        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-            self.input.borrow_mut().read(buf)
+    impl<'lt> Read for &DummySocket<'lt> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if let Some(kind) = self.read_errors.borrow_mut().pop_front() {
+                return Err(kind.into());
+            }
+            if let Some((max_len, clock, delay)) = self.slow_reads.get() {
+                clock.advance(delay);
+                let len = buf.len().min(max_len);
+                return self.input.borrow_mut().read(&mut buf[..len]);
+            }
+            self.input.borrow_mut().read(buf)
+        }
+    }
+
+    impl<'lt> Write for &DummySocket<'lt> {
+        fn write(&mut self, buf: &[u8]) -> std::result::Result<usize, std::io::Error> {
+            if let Some((clock, delay)) = self.slow_writes.get() {
+                clock.advance(delay);
+            }
+            self.output.borrow_mut().write(buf)
+        }
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> std::io::Result<usize> {
+            if let Some((clock, delay)) = self.slow_writes.get() {
+                clock.advance(delay);
+            }
+            self.output.borrow_mut().write_vectored(bufs)
+        }
+        fn flush(&mut self) -> std::result::Result<(), std::io::Error> {
+            if let Some(kind) = self.flush_errors.borrow_mut().pop_front() {
+                return Err(kind.into());
+            }
+            self.output.borrow_mut().flush()
+        }
+    }
+
+    /// Helper function to test [`PolicyRequestHandler`] implementations using the line format.
+    /// Expects `input` to contain one (or more) policy requests. \
+    /// Will use `ctx` as context parameter in `handle_connection`. \
+    /// If `handle_connection` returns successfully, it returns `Ok(..)` containing the complete response(s) that would be sent to the mail server. \
+    /// If `handle_connection` fails, the Error response will be passed through and the response(s) up to that point are discarded.
+    /// ## Example
+    /// ```norun
+    /// let input = b"request=smtpd_access_policy\nprotocol_state=RCPT\n...\n\n";
+    /// assert_eq!(
+    ///     handle_connection_response::<MyRequestHandler, _, _>(input, &()).unwrap(),
+    ///     b"action=DEFER some_message\n\n"
+    /// );
+    /// ```
+    ///
+    /// [`PolicyRequestHandler`]: ../trait.PolicyRequestHandler.html
+    pub fn handle_connection_response<'l, HandlerType, ContextType, ErrorType>(
+        input: &'l [u8],
+        ctx: &'l ContextType,
+    ) -> Result<Vec<u8>, PostfixPolicyError<ErrorType>>
+    where
+        HandlerType: PolicyRequestHandler<'l, ContextType, ErrorType>,
+    {
+        let socket = DummySocket::new(input);
+        handle_connection::<HandlerType, ContextType, ErrorType, _>(&socket, ctx)?;
+        Ok(socket.get_output())
+    }
+
+    /// Helper function to check the exact framing of [`handle_connection_response`] output. \
+    /// Splits `output` into its responses and parses each of them. \
+    /// Panics if any byte doesn't belong to a response of the form `action=...\n\n`, e.g. if there is trailing data, a
+    /// terminator is missing or an action can't be parsed.
+    /// ## Example
+    /// ```norun
+    /// let output = handle_connection_response::<MyRequestHandler, _, _>(input, &()).unwrap();
+    /// assert_eq!(split_responses(&output), vec![PolicyResponse::Dunno, PolicyResponse::Reject(Vec::new())]);
+    /// ```
+    ///
+    /// [`handle_connection_response`]: fn.handle_connection_response.html
+    pub fn split_responses(output: &[u8]) -> Vec<PolicyResponse> {
+        let mut responses = Vec::new();
+        let mut rest = output;
+        while !rest.is_empty() {
+            let offset = output.len() - rest.len();
+            let line_end = match rest.iter().position(|&c| c == b'\n') {
+                Some(pos) => pos,
+                None => panic!(
+                    "unterminated response at byte {}: {:?}",
+                    offset,
+                    String::from_utf8_lossy(rest)
+                ),
+            };
+            if rest.get(line_end + 1) != Some(&b'\n') {
+                panic!(
+                    "response at byte {} isn't followed by an empty line: {:?}",
+                    offset,
+                    String::from_utf8_lossy(rest)
+                );
+            }
+            let line = &rest[..line_end];
+            let response = line
+                .strip_prefix(b"action=")
+                .and_then(PolicyResponse::parse)
+                .unwrap_or_else(|| {
+                    panic!(
+                        "invalid response at byte {}: {:?}",
+                        offset,
+                        String::from_utf8_lossy(line)
+                    )
+                });
+            responses.push(response);
+            rest = &rest[line_end + 2..];
+        }
+        responses
+    }
+
+    /// Helper function for regression tests against captured traffic. \
+    /// Reads the requests in the file at `path`, each ended by an empty line like on the wire, and runs them through
+    /// `HandlerType` using [`handle_connection_split`]. Returns the responses in order, checked with
+    /// [`split_responses`].
+    /// ## Example
+    /// ```norun
+    /// let responses = replay_file::<MyRequestHandler, _, _>("testdata/captured.txt", &()).unwrap();
+    /// assert_eq!(responses.len(), 2);
+    /// ```
+    ///
+    /// [`handle_connection_split`]: ../fn.handle_connection_split.html
+    /// [`split_responses`]: fn.split_responses.html
+    pub fn replay_file<'l, HandlerType, ContextType, ErrorType>(
+        path: impl AsRef<std::path::Path>,
+        ctx: &'l ContextType,
+    ) -> Result<Vec<PolicyResponse>, PostfixPolicyError<ErrorType>>
+    where
+        HandlerType: PolicyRequestHandler<'l, ContextType, ErrorType>,
+    {
+        let reader = BufReader::new(File::open(path)?);
+        let mut output = Vec::new();
+        handle_connection_split::<HandlerType, ContextType, ErrorType, _, _>(reader, &mut output, ctx)?;
+        Ok(split_responses(&output))
+    }
+
+    /// Client side of the protocol for integration tests against a running policy server.
+    /// Sends one request consisting of `attributes` over `stream` and returns the parsed response. \
+    /// Fails with `ErrorKind::InvalidData` if the server's response can't be parsed and
+    /// `ErrorKind::UnexpectedEof` if the server closed the connection instead of responding.
+    /// ## Example
+    /// ```norun
+    /// let mut stream = UnixStream::connect(socket_path)?;
+    /// let response = send_request(&mut stream, &[(b"request", b"smtpd_access_policy"), (b"protocol_state", b"RCPT")])?;
+    /// assert_eq!(response, PolicyResponse::Dunno);
+    /// ```
+    pub fn send_request<StreamType: Read + Write>(
+        stream: &mut StreamType,
+        attributes: &[(&[u8], &[u8])],
+    ) -> std::io::Result<PolicyResponse> {
+        stream.write_all(&encode_request(attributes))?;
+        stream.flush()?;
+
+        let mut reader = BufReader::new(stream);
+        let mut line = Vec::new();
+        let mut terminator = Vec::new();
+        reader.read_until(b'\n', &mut line)?;
+        reader.read_until(b'\n', &mut terminator)?;
+        if terminator.is_empty() {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        line.strip_prefix(b"action=")
+            .and_then(|rest| rest.strip_suffix(b"\n"))
+            .filter(|_| terminator == b"\n")
+            .and_then(PolicyResponse::parse)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid response: {:?}", String::from_utf8_lossy(&line)),
+                )
+            })
+    }
+
+    /// Helper function to test the decision of a [`PolicyRequestHandler`] implementation. \
+    /// Expects `input` to contain exactly one policy request and asserts that the handler responds with `expected`. \
+    /// Panics with a readable message if `handle_connection` fails, the response can't be parsed or doesn't match.
+    /// ## Example
+    /// ```norun
+    /// let input = b"request=smtpd_access_policy\nprotocol_state=RCPT\n...\n\n";
+    /// assert_response::<MyRequestHandler, _, _>(input, &(), PolicyResponse::Defer(b"some_message".to_vec()));
+    /// ```
+    ///
+    /// [`PolicyRequestHandler`]: ../trait.PolicyRequestHandler.html
+    pub fn assert_response<'l, HandlerType, ContextType, ErrorType>(
+        input: &'l [u8],
+        ctx: &'l ContextType,
+        expected: PolicyResponse,
+    ) where
+        HandlerType: PolicyRequestHandler<'l, ContextType, ErrorType>,
+        ErrorType: std::fmt::Debug,
+    {
+        let socket = DummySocket::new(input);
+        let response = match handled_response::<HandlerType, ContextType, ErrorType>(&socket, ctx) {
+            Ok(response) => response,
+            Err(message) => panic!("{}", message),
+        };
+        assert_eq!(
+            response, expected,
+            "handler responded {:?}, expected {:?}",
+            response, expected
+        );
+    }
+
+    /// Helper function to test a [`PolicyRequestHandler`] implementation against a table of scenarios. \
+    /// Each case consists of the attributes of one request and the response the handler is expected to give. \
+    /// All cases are run, then the test panics listing every case that failed. \
+    /// Each case gets a new handler created with `ctx`.
+    /// ## Example
+    /// ```norun
+    /// run_cases::<MyRequestHandler, _, _>(
+    ///     vec![
+    ///         (vec![(&b"client_address"[..], &b"192.0.2.1"[..])], PolicyResponse::Dunno),
+    ///         (vec![(&b"client_address"[..], &b"198.51.100.7"[..])], PolicyResponse::Reject(Vec::new())),
+    ///     ],
+    ///     &(),
+    /// );
+    /// ```
+    ///
+    /// [`PolicyRequestHandler`]: ../trait.PolicyRequestHandler.html
+    pub fn run_cases<'l, 'a, HandlerType, ContextType, ErrorType>(
+        cases: impl IntoIterator<Item = (Vec<(&'a [u8], &'a [u8])>, PolicyResponse)>,
+        ctx: &'l ContextType,
+    ) where
+        HandlerType: PolicyRequestHandler<'l, ContextType, ErrorType>,
+        ErrorType: std::fmt::Debug,
+    {
+        let mut failures = Vec::new();
+        for (index, (attributes, expected)) in cases.into_iter().enumerate() {
+            let input = encode_request(&attributes);
+            let socket = DummySocket::new(&input);
+            match handled_response::<HandlerType, ContextType, ErrorType>(&socket, ctx) {
+                Ok(response) if response == expected => {}
+                Ok(response) => failures.push(format!(
+                    "case {}: handler responded {:?}, expected {:?}",
+                    index, response, expected
+                )),
+                Err(message) => failures.push(format!("case {}: {}", index, message)),
+            }
+        }
+        if !failures.is_empty() {
+            panic!("{} case(s) failed:\n{}", failures.len(), failures.join("\n"));
+        }
+    }
+
+    /// Runs the handler on the requests read from `socket`, expecting exactly one response.
+    fn handled_response<'l, HandlerType, ContextType, ErrorType>(
+        socket: &DummySocket,
+        ctx: &'l ContextType,
+    ) -> Result<PolicyResponse, String>
+    where
+        HandlerType: PolicyRequestHandler<'l, ContextType, ErrorType>,
+        ErrorType: std::fmt::Debug,
+    {
+        if let Err(e) = handle_connection::<HandlerType, ContextType, ErrorType, _>(socket, ctx) {
+            return Err(format!("handle_connection failed: {:?}", e));
+        }
+        let output = socket.output.borrow();
+        let action = output
+            .strip_prefix(b"action=")
+            .and_then(|rest| rest.strip_suffix(b"\n\n"))
+            .ok_or_else(|| format!("not a single response: {:?}", String::from_utf8_lossy(&output)))?;
+        PolicyResponse::parse(action)
+            .ok_or_else(|| format!("unparseable response: {:?}", String::from_utf8_lossy(&output)))
+    }
+
+    /// Encodes `attributes` as one request.
+    fn encode_request(attributes: &[(&[u8], &[u8])]) -> Vec<u8> {
+        let mut request = Vec::new();
+        for (name, value) in attributes {
+            request.extend_from_slice(name);
+            request.push(b'=');
+            request.extend_from_slice(value);
+            request.push(b'\n');
+        }
+        request.push(b'\n');
+        request
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::test_helper::{
+        assert_response, handle_connection_response, replay_file, run_cases, split_responses, DummySocket, FakeClock,
+    };
+    use super::{
+        flush_response, handle_connection, handle_connection_split, handle_connection_with_leftover,
+        handle_connection_with_limits, handle_connection_with_metrics, handle_connection_with_response_bytes,
+        handle_framed_request, handle_one, process_request, CopyingWriter, LimitKind, Limits, Metrics, NoMetrics,
+        OnError, PolicyRequestHandler, PolicyResponse, PostfixPolicyError, RequestTimings, ResponseError,
+        ResponseTerminator, SystemClock,
+    };
+    use std::cell::RefCell;
+    use std::io::{BufReader, Cursor, ErrorKind, IoSlice, Read, Write};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    struct DummyRequestHandler {
+        found_request: bool,
+        client_address: Vec<u8>,
+    }
+    impl<'l> PolicyRequestHandler<'l, (), ()> for DummyRequestHandler {
+        fn new(_: &()) -> Self {
+            Self {
+                found_request: false,
+                client_address: vec![],
+            }
+        }
+        fn attribute(&mut self, name: &[u8], value: &[u8]) -> Option<()> {
+            match name {
+                b"request" => self.found_request = true,
+                b"client_address" => self.client_address = value.to_vec(),
+                _ => {}
+            }
+            None
+        }
+
+        fn response(self) -> Result<PolicyResponse, ()> {
+            if !self.found_request {
+                return Ok(PolicyResponse::Reject(Vec::new()));
+            }
+            Ok(PolicyResponse::Defer(self.client_address.clone()))
+        }
+    }
+
+    #[test]
+    fn test_handle_connection_valid() {
+        let input =
+            b"request=smtpd_access_policy\nprotocol_state=RCPT\nprotocol_name=ESMTP\nclient_address=131.234.189.14\n\n";
+        assert_response::<DummyRequestHandler, _, _>(input, &(), PolicyResponse::Defer(b"131.234.189.14".to_vec()));
+    }
+
+    #[test]
+    #[should_panic(expected = "handler responded Defer")]
+    fn test_assert_response_mismatch() {
+        let input = b"request=smtpd_access_policy\n\n";
+        assert_response::<DummyRequestHandler, _, _>(input, &(), PolicyResponse::Dunno);
+    }
+
+    #[test]
+    fn test_handle_connection_empty() {
+        let input = b"\n";
+        assert_eq!(
+            handle_connection_response::<DummyRequestHandler, _, _>(input, &()).unwrap(),
+            b"action=REJECT\n\n"
+        );
+    }
+
+    #[test]
+    fn test_handle_connection_line_without_eq() {
+        let input = b"asdf\n\n";
+
+        assert!(
+            match handle_connection_response::<DummyRequestHandler, _, _>(input, &()) {
+                Err(PostfixPolicyError::ProtocolError(l)) => {
+                    assert_eq!(&l, b"asdf\n");
+                    true
+                }
+                _ => false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_handle_connection_line_empty_name() {
+        let input = b"=a\n\n";
+
+        assert!(
+            match handle_connection_response::<DummyRequestHandler, _, _>(input, &()) {
+                Err(PostfixPolicyError::ProtocolError(l)) => {
+                    assert_eq!(&l, b"=a\n");
+                    true
+                }
+                _ => false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_handle_connection_flush_retry() {
+        let input = b"request=smtpd_access_policy\nclient_address=131.234.189.14\n\n";
+        let limits = Limits {
+            retry_interrupted_flush: true,
+            ..Limits::default()
+        };
+        let socket = DummySocket::new(input);
+        socket.fail_flush(ErrorKind::Interrupted);
+        handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &limits).unwrap();
+        assert_eq!(socket.get_output(), b"action=DEFER 131.234.189.14\n\n");
+    }
+
+    #[test]
+    fn test_handle_connection_flush_no_retry() {
+        let input = b"request=smtpd_access_policy\n\n";
+        let limits = Limits {
+            retry_interrupted_flush: true,
+            ..Limits::default()
+        };
+        let socket = DummySocket::new(input);
+        socket.fail_flush(ErrorKind::BrokenPipe);
+        assert!(matches!(
+            handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &limits),
+            Err(PostfixPolicyError::IoError(ref e)) if e.kind() == ErrorKind::BrokenPipe
+        ));
+
+        let socket = DummySocket::new(input);
+        socket.fail_flush(ErrorKind::Interrupted);
+        assert!(matches!(
+            handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &Limits::default()),
+            Err(PostfixPolicyError::IoError(ref e)) if e.kind() == ErrorKind::Interrupted
+        ));
+    }
+
+    struct CountingRequestHandler {
+        count: usize,
+    }
+    impl<'l> PolicyRequestHandler<'l, (), ()> for CountingRequestHandler {
+        fn new(_: &()) -> Self {
+            Self { count: 0 }
+        }
+        fn attribute(&mut self, _: &[u8], _: &[u8]) -> Option<()> {
+            self.count += 1;
+            None
+        }
+        fn response(self) -> Result<PolicyResponse, ()> {
+            Ok(PolicyResponse::Info(self.count.to_string().into_bytes()))
+        }
+    }
+
+    /// Generates a request with `remaining` attributes on the fly, so the test input isn't held in memory either.
+    struct ManyAttributesReader {
+        remaining: usize,
+        finished: bool,
+    }
+    impl Read for ManyAttributesReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let mut written = 0;
+            while self.remaining > 0 && buf.len() - written >= 4 {
+                buf[written..written + 4].copy_from_slice(b"a=1\n");
+                written += 4;
+                self.remaining -= 1;
+            }
+            if self.remaining == 0 && !self.finished && written < buf.len() {
+                buf[written] = b'\n';
+                written += 1;
+                self.finished = true;
+            }
+            Ok(written)
+        }
+    }
+
+    #[test]
+    fn test_handle_connection_many_attributes() {
+        let reader = BufReader::new(ManyAttributesReader {
+            remaining: 1_000_000,
+            finished: false,
+        });
+        let mut output: Vec<u8> = Vec::new();
+        handle_connection_split::<CountingRequestHandler, _, _, _, _>(reader, &mut output, &()).unwrap();
+        assert_eq!(output, b"action=INFO 1000000\n\n");
+    }
+
+    #[test]
+    fn test_handle_connection_max_attributes_then_respond() {
+        let input = b"a=1\nb=2\nc=3\nd=4\ne=5\n\nf=6\n\n";
+        let limits = Limits {
+            max_attributes_then_respond: Some(3),
+            ..Limits::default()
+        };
+        let socket = DummySocket::new(input);
+        handle_connection_with_limits::<CountingRequestHandler, _, _, _>(&socket, &(), &limits).unwrap();
+        assert_eq!(socket.get_output(), b"action=INFO 3\n\naction=INFO 1\n\n");
+    }
+
+    struct SlowRequestHandler<'l> {
+        clock: &'l FakeClock,
+    }
+    impl<'l> PolicyRequestHandler<'l, FakeClock, ()> for SlowRequestHandler<'l> {
+        fn new(clock: &'l FakeClock) -> Self {
+            Self { clock }
+        }
+        fn attribute(&mut self, _: &[u8], _: &[u8]) -> Option<()> {
+            self.clock.advance(Duration::from_millis(1));
+            None
+        }
+        fn response(self) -> Result<PolicyResponse, ()> {
+            self.clock.advance(Duration::from_millis(20));
+            Ok(PolicyResponse::Dunno)
+        }
+    }
+
+    #[derive(Default)]
+    struct RecordingMetrics {
+        durations: Vec<Duration>,
+        protocol_states: Vec<Vec<u8>>,
+        timings: Vec<RequestTimings>,
+        parsed: Vec<(usize, usize)>,
+    }
+    impl Metrics for RecordingMetrics {
+        fn on_request_complete(&mut self, protocol_state: &[u8], duration: Duration) {
+            self.durations.push(duration);
+            self.protocol_states.push(protocol_state.to_vec());
+        }
+        fn on_request_timings(&mut self, _protocol_state: &[u8], timings: &RequestTimings) {
+            self.timings.push(*timings);
+        }
+        fn on_request_parsed(&mut self, size_bytes: usize, attribute_count: usize) {
+            self.parsed.push((size_bytes, attribute_count));
+        }
+    }
+
+    #[test]
+    fn test_handle_connection_request_parsed() {
+        let input = b"request=smtpd_access_policy\nprotocol_state=RCPT\nclient_address=1.2.3.4\n\n\
+        request=smtpd_access_policy\n\n";
+        let mut metrics = RecordingMetrics::default();
+        let socket = DummySocket::new(input);
+        handle_connection_with_metrics::<DummyRequestHandler, _, _, _, _, _>(
+            &socket,
+            &(),
+            &Limits::default(),
+            &mut metrics,
+            &SystemClock,
+        )
+        .unwrap();
+        assert_eq!(metrics.parsed, vec![(72, 3), (29, 1)]);
+    }
+
+    #[test]
+    fn test_handle_connection_request_timings() {
+        let input = b"request=smtpd_access_policy\nprotocol_state=RCPT\n\n";
+        let clock = FakeClock::new();
+        let mut metrics = RecordingMetrics::default();
+        let socket = DummySocket::new(input);
+        socket.slow_writes(&clock, Duration::from_millis(3));
+        handle_connection_with_metrics::<SlowRequestHandler, _, _, _, _, _>(
+            &socket,
+            &clock,
+            &Limits::default(),
+            &mut metrics,
+            &clock,
+        )
+        .unwrap();
+        assert_eq!(
+            metrics.timings,
+            vec![RequestTimings {
+                read: Duration::from_millis(2),
+                compute: Duration::from_millis(20),
+                write: Duration::from_millis(3),
+            }]
+        );
+        assert_eq!(metrics.durations, vec![Duration::from_millis(25)]);
+    }
+
+    #[test]
+    fn test_handle_connection_request_duration() {
+        let input = b"request=smtpd_access_policy\nprotocol_state=RCPT\n\nrequest=smtpd_access_policy\n\n";
+        let clock = FakeClock::new();
+        let mut metrics = RecordingMetrics::default();
+        let socket = DummySocket::new(input);
+        handle_connection_with_metrics::<SlowRequestHandler, _, _, _, _, _>(
+            &socket,
+            &clock,
+            &Limits::default(),
+            &mut metrics,
+            &clock,
+        )
+        .unwrap();
+        assert_eq!(
+            metrics.durations,
+            vec![Duration::from_millis(22), Duration::from_millis(21)]
+        );
+        assert_eq!(metrics.protocol_states, vec![b"RCPT".to_vec(), Vec::new()]);
+    }
+
+    #[test]
+    fn test_handle_connection_leftover() {
+        let input = b"request=smtpd_access_policy\nclient_address=1.2.3.4\n\nrequest=smtpd_access_policy\nclient_addr";
+        let socket = DummySocket::new(input);
+        let (result, leftover) = handle_connection_with_leftover::<DummyRequestHandler, _, _, _>(&socket, &());
+        assert!(matches!(result, Err(PostfixPolicyError::ProtocolError(_))));
+        assert_eq!(leftover, b"request=smtpd_access_policy\nclient_addr");
+        assert_eq!(socket.get_output(), b"action=DEFER 1.2.3.4\n\n");
+
+        let input = b"request=smtpd_access_policy\n\nrequest=smtpd_access_policy\n";
+        let socket = DummySocket::new(input);
+        let (result, leftover) = handle_connection_with_leftover::<DummyRequestHandler, _, _, _>(&socket, &());
+        assert!(result.is_ok());
+        assert_eq!(leftover, b"request=smtpd_access_policy\n");
+    }
+
+    #[test]
+    fn test_handle_connection_response_bytes() {
+        let input = b"request=smtpd_access_policy\nclient_address=1.2.3.4\n\nrequest=smtpd_access_policy\n\nasdf\n\n";
+        let socket = DummySocket::new(input);
+        let limits = Limits {
+            on_protocol_error_response: Some(PolicyResponse::Dunno),
+            ..Limits::default()
+        };
+        let (result, bytes) =
+            handle_connection_with_response_bytes::<DummyRequestHandler, _, _, _>(&socket, &(), &limits);
+        assert!(result.is_ok());
+        assert_eq!(bytes, b"action=DEFER 1.2.3.4\n\naction=DEFER\n\naction=DUNNO\n\n");
+        assert_eq!(socket.get_output(), bytes);
+
+        // with a writer taking only a few bytes at a time, the copy still matches the output
+        let mut writer = CopyingWriter {
+            inner: ShortWriter::default(),
+            copy: Vec::new(),
+        };
+        flush_response::<_, ()>(&mut writer, PolicyResponse::Reject(b"no".to_vec())).unwrap();
+        assert_eq!(writer.copy, b"action=REJECT no\n\n");
+        assert_eq!(writer.inner.output, writer.copy);
+    }
+
+    #[test]
+    fn test_handle_connection_protocol_version() {
+        let limits = Limits {
+            accepted_protocol_versions: Some(vec![b"1".to_vec(), b"2".to_vec()]),
+            ..Limits::default()
+        };
+        let input = b"request=smtpd_access_policy\nprotocol_version=3\n\n";
+        let socket = DummySocket::new(input);
+        assert!(matches!(
+            handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &limits),
+            Err(PostfixPolicyError::UnsupportedProtocolVersion(ref v)) if v == b"3"
+        ));
+        assert_eq!(socket.get_output(), b"");
+
+        let input = b"request=smtpd_access_policy\npolicy_protocol=2\n\nrequest=smtpd_access_policy\n\n";
+        let socket = DummySocket::new(input);
+        handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &limits).unwrap();
+        assert_eq!(socket.get_output(), b"action=DEFER\n\naction=DEFER\n\n");
+
+        let input = b"request=smtpd_access_policy\nprotocol_version=3\n\n";
+        let socket = DummySocket::new(input);
+        handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &Limits::default()).unwrap();
+        assert_eq!(socket.get_output(), b"action=DEFER\n\n");
+    }
+
+    struct StoppingContext {
+        should_stop: Arc<AtomicBool>,
+        attributes: AtomicUsize,
+    }
+
+    struct StoppingRequestHandler<'l> {
+        ctx: &'l StoppingContext,
+    }
+    impl<'l> PolicyRequestHandler<'l, StoppingContext, ()> for StoppingRequestHandler<'l> {
+        fn new(ctx: &'l StoppingContext) -> Self {
+            Self { ctx }
+        }
+        fn attribute(&mut self, _: &[u8], _: &[u8]) -> Option<()> {
+            self.ctx.attributes.fetch_add(1, Ordering::SeqCst);
+            None
+        }
+        fn response(self) -> Result<PolicyResponse, ()> {
+            self.ctx.should_stop.store(true, Ordering::SeqCst);
+            Ok(PolicyResponse::Dunno)
+        }
+    }
+
+    #[test]
+    fn test_handle_connection_should_stop() {
+        let ctx = StoppingContext {
+            should_stop: Arc::new(AtomicBool::new(false)),
+            attributes: AtomicUsize::new(0),
+        };
+        let limits = Limits {
+            should_stop: Some(ctx.should_stop.clone()),
+            ..Limits::default()
+        };
+        let input = b"request=smtpd_access_policy\nprotocol_state=RCPT\n\nrequest=smtpd_access_policy\n\n";
+        let socket = DummySocket::new(input);
+        handle_connection_with_limits::<StoppingRequestHandler, _, _, _>(&socket, &ctx, &limits).unwrap();
+        assert_eq!(socket.get_output(), b"action=DUNNO\n\n");
+        assert_eq!(ctx.attributes.load(Ordering::SeqCst), 2);
+
+        let socket = DummySocket::new(input);
+        handle_connection_with_limits::<StoppingRequestHandler, _, _, _>(&socket, &ctx, &limits).unwrap();
+        assert_eq!(socket.get_output(), b"");
+        assert_eq!(ctx.attributes.load(Ordering::SeqCst), 2);
+    }
+
+    /// Fails in `attribute` for the client address `fail` and in `response` for `fail-later`.
+    struct FailingRequestHandler {
+        client_address: Vec<u8>,
+    }
+    impl<'l> PolicyRequestHandler<'l, (), ()> for FailingRequestHandler {
+        fn new(_: &()) -> Self {
+            Self {
+                client_address: Vec::new(),
+            }
+        }
+        fn attribute(&mut self, name: &[u8], value: &[u8]) -> Option<()> {
+            if name == b"client_address" {
+                if value == b"fail" {
+                    return Some(());
+                }
+                self.client_address = value.to_vec();
+            }
+            None
+        }
+        fn response(self) -> Result<PolicyResponse, ()> {
+            if self.client_address == b"fail-later" {
+                return Err(());
+            }
+            Ok(PolicyResponse::Defer(self.client_address))
+        }
+    }
+
+    type AttributeLog = RefCell<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    struct LoggingRequestHandler<'l> {
+        log: &'l AttributeLog,
+    }
+    impl<'l> PolicyRequestHandler<'l, AttributeLog, ()> for LoggingRequestHandler<'l> {
+        fn new(log: &'l AttributeLog) -> Self {
+            Self { log }
+        }
+        fn attribute(&mut self, name: &[u8], value: &[u8]) -> Option<()> {
+            self.log.borrow_mut().push((name.to_vec(), value.to_vec()));
+            None
+        }
+        fn response(self) -> Result<PolicyResponse, ()> {
+            Ok(PolicyResponse::Dunno)
+        }
+    }
+
+    #[test]
+    fn test_handle_connection_unterminated_line() {
+        let log = AttributeLog::default();
+        let input = b"request=smtpd_access_policy\nclient_address=1.2.3.4";
+        assert_eq!(
+            handle_connection_response::<LoggingRequestHandler, _, _>(input, &log).unwrap(),
+            b""
+        );
+        assert_eq!(
+            log.into_inner(),
+            vec![
+                (b"request".to_vec(), b"smtpd_access_policy".to_vec()),
+                (b"client_address".to_vec(), b"1.2.3.4".to_vec()),
+            ]
+        );
+
+        let log = AttributeLog::default();
+        let input = b"client_name=\nclient_address=";
+        handle_connection_response::<LoggingRequestHandler, _, _>(input, &log).unwrap();
+        assert_eq!(
+            log.into_inner(),
+            vec![
+                (b"client_name".to_vec(), Vec::new()),
+                (b"client_address".to_vec(), Vec::new())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_handle_connection_require_terminated_lines() {
+        let input = b"request=smtpd_access_policy\nclient_address=1.2.3.4";
+        let log = AttributeLog::default();
+        let socket = DummySocket::new(input);
+        handle_connection_with_limits::<LoggingRequestHandler, _, _, _>(&socket, &log, &Limits::default()).unwrap();
+        assert_eq!(log.into_inner().len(), 2);
+
+        let limits = Limits {
+            require_terminated_lines: true,
+            ..Limits::default()
+        };
+        let log = AttributeLog::default();
+        let socket = DummySocket::new(input);
+        assert!(matches!(
+            handle_connection_with_limits::<LoggingRequestHandler, _, _, _>(&socket, &log, &limits),
+            Err(PostfixPolicyError::UnterminatedLine(ref line)) if line == b"client_address=1.2.3.4"
+        ));
+        assert_eq!(
+            log.into_inner(),
+            vec![(b"request".to_vec(), b"smtpd_access_policy".to_vec())]
+        );
+
+        let socket = DummySocket::new(b"request=smtpd_access_policy\n\n");
+        handle_connection_with_limits::<LoggingRequestHandler, _, _, _>(&socket, &AttributeLog::default(), &limits)
+            .unwrap();
+        assert_eq!(socket.get_output(), b"action=DUNNO\n\n");
+    }
+
+    #[test]
+    fn test_handle_connection_protocol_error_response() {
+        let limits = Limits {
+            on_protocol_error_response: Some(PolicyResponse::Defer(b"4.3.0 policy temporarily unavailable".to_vec())),
+            ..Limits::default()
+        };
+        let input = b"request=smtpd_access_policy\n\nrequest=smtpd_access_policy\ngarbage\nclient_address=1.2.3.4\n\n";
+        let socket = DummySocket::new(input);
+        handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &limits).unwrap();
+        assert_eq!(
+            socket.get_output(),
+            b"action=DEFER\n\naction=DEFER 4.3.0 policy temporarily unavailable\n\n"
+        );
+    }
+
+    #[test]
+    fn test_handle_connection_utf8_attributes() {
+        let limits = Limits {
+            utf8_attributes: vec![b"helo_name".to_vec()],
+            ..Limits::default()
+        };
+        let input = b"request=smtpd_access_policy\nhelo_name=mx.\xc3\xa4.example\nclient_name=\xff\n\n";
+        let socket = DummySocket::new(input);
+        handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &limits).unwrap();
+        assert_eq!(socket.get_output(), b"action=DEFER\n\n");
+
+        let input = b"request=smtpd_access_policy\nhelo_name=mx.\xff.example\n\n";
+        let socket = DummySocket::new(input);
+        assert!(matches!(
+            handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &limits),
+            Err(PostfixPolicyError::InvalidUtf8 { ref name }) if name == b"helo_name"
+        ));
+    }
+
+    #[test]
+    fn test_handle_connection_deliver_empty_values() {
+        let input = b"request=smtpd_access_policy\nclient_name=\nclient_address=1.2.3.4\n\n";
+        let log = AttributeLog::default();
+        let socket = DummySocket::new(input);
+        handle_connection_with_limits::<LoggingRequestHandler, _, _, _>(&socket, &log, &Limits::default()).unwrap();
+        assert_eq!(
+            log.into_inner(),
+            vec![
+                (b"request".to_vec(), b"smtpd_access_policy".to_vec()),
+                (b"client_name".to_vec(), Vec::new()),
+                (b"client_address".to_vec(), b"1.2.3.4".to_vec()),
+            ]
+        );
+
+        let limits = Limits {
+            deliver_empty_values: false,
+            ..Limits::default()
+        };
+        let log = AttributeLog::default();
+        let socket = DummySocket::new(input);
+        handle_connection_with_limits::<LoggingRequestHandler, _, _, _>(&socket, &log, &limits).unwrap();
+        assert_eq!(socket.get_output(), b"action=DUNNO\n\n");
+        assert_eq!(
+            log.into_inner(),
+            vec![
+                (b"request".to_vec(), b"smtpd_access_policy".to_vec()),
+                (b"client_address".to_vec(), b"1.2.3.4".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_handle_connection_read_timeout_errors() {
+        let stop = Arc::new(AtomicBool::new(false));
+        let limits = Limits {
+            read_timeout: Some(Duration::from_secs(1)),
+            should_stop: Some(stop.clone()),
+            ..Limits::default()
+        };
+        let input = b"request=smtpd_access_policy\nclient_address=1.2.3.4\n\n";
+
+        // a socket timeout before a request keeps waiting
+        let socket = DummySocket::new(input);
+        socket.fail_read(ErrorKind::TimedOut);
+        handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &limits).unwrap();
+        assert_eq!(socket.get_output(), b"action=DEFER 1.2.3.4\n\n");
+
+        // unless the connection is asked to stop
+        stop.store(true, Ordering::SeqCst);
+        let socket = DummySocket::new(input);
+        socket.fail_read(ErrorKind::TimedOut);
+        handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &limits).unwrap();
+        assert_eq!(socket.get_output(), b"");
+        stop.store(false, Ordering::SeqCst);
+
+        // non-blocking sockets still fail
+        let socket = DummySocket::new(input);
+        socket.fail_read(ErrorKind::WouldBlock);
+        assert!(matches!(
+            handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &limits),
+            Err(PostfixPolicyError::WouldBlock)
+        ));
+    }
+
+    #[test]
+    fn test_handle_connection_would_block() {
+        let socket = DummySocket::new(b"request=smtpd_access_policy\nclient_address=1.2.3.4\n\n");
+        socket.fail_read(ErrorKind::WouldBlock);
+        assert!(matches!(
+            handle_connection::<DummyRequestHandler, _, _, _>(&socket, &()),
+            Err(PostfixPolicyError::WouldBlock)
+        ));
+        assert_eq!(socket.get_output(), b"");
+
+        let socket = DummySocket::new(b"request=smtpd_access_policy\nclient_address=1.2.3.4\n\n");
+        socket.fail_read(ErrorKind::Interrupted);
+        handle_connection::<DummyRequestHandler, _, _, _>(&socket, &()).unwrap();
+        assert_eq!(socket.get_output(), b"action=DEFER 1.2.3.4\n\n");
+    }
+
+    #[test]
+    fn test_handle_connection_validate_attribute() {
+        let validate = |_: &[u8], value: &[u8]| {
+            if value.iter().any(u8::is_ascii_control) {
+                return Err(());
+            }
+            Ok(())
+        };
+        let limits = Limits {
+            validate_attribute: Some(Arc::new(validate)),
+            ..Limits::default()
+        };
+        let socket = DummySocket::new(b"request=smtpd_access_policy\nclient_address=1.2.3.4\n\n");
+        handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &limits).unwrap();
+        assert_eq!(socket.get_output(), b"action=DEFER 1.2.3.4\n\n");
+
+        let socket = DummySocket::new(b"request=smtpd_access_policy\nclient_address=1.2.3.4\x07\n\n");
+        assert!(matches!(
+            handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &limits),
+            Err(PostfixPolicyError::HandlerError(()))
+        ));
+        assert_eq!(socket.get_output(), b"");
+    }
+
+    #[test]
+    fn test_run_cases() {
+        run_cases::<DummyRequestHandler, _, _>(
+            vec![
+                (
+                    vec![
+                        (&b"request"[..], &b"smtpd_access_policy"[..]),
+                        (b"client_address", b"192.0.2.1"),
+                    ],
+                    PolicyResponse::Defer(b"192.0.2.1".to_vec()),
+                ),
+                (
+                    vec![(&b"request"[..], &b"smtpd_access_policy"[..])],
+                    PolicyResponse::Defer(Vec::new()),
+                ),
+                (
+                    vec![(&b"client_address"[..], &b"192.0.2.1"[..])],
+                    PolicyResponse::Reject(Vec::new()),
+                ),
+            ],
+            &(),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "2 case(s) failed")]
+    fn test_run_cases_failures() {
+        run_cases::<DummyRequestHandler, _, _>(
+            vec![
+                (
+                    vec![(&b"request"[..], &b"smtpd_access_policy"[..])],
+                    PolicyResponse::Dunno,
+                ),
+                (
+                    vec![(&b"request"[..], &b"smtpd_access_policy"[..])],
+                    PolicyResponse::Defer(Vec::new()),
+                ),
+                (vec![(&b"a"[..], &b"b"[..])], PolicyResponse::Ok),
+            ],
+            &(),
+        );
+    }
+
+    #[test]
+    fn test_handle_connection_max_name_len() {
+        let limits = Limits {
+            max_name_len: Some(32),
+            ..Limits::default()
+        };
+        let mut input = vec![b'a'; 10 * 1024];
+        input.extend_from_slice(b"=1\n\n");
+        let socket = DummySocket::new(&input);
+        assert!(matches!(
+            handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &limits),
+            Err(PostfixPolicyError::LimitExceeded {
+                limit: LimitKind::MaxNameLen
+            })
+        ));
+
+        let mut input = b"request=smtpd_access_policy\nclient_address=".to_vec();
+        input.extend_from_slice(&[b'1'; 1024]);
+        input.extend_from_slice(b"\n\n");
+        let socket = DummySocket::new(&input);
+        handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &limits).unwrap();
+        assert!(socket.get_output().starts_with(b"action=DEFER 111"));
+
+        // the name is checked while reading, so an endless name fails without an endless buffer
+        let mut reader = BufReader::new(std::io::repeat(b'a'));
+        let mut handler = DummyRequestHandler::new(&());
+        assert!(matches!(
+            super::read_request(&mut reader, &mut handler, &limits, &SystemClock, None),
+            Err(PostfixPolicyError::LimitExceeded {
+                limit: LimitKind::MaxNameLen
+            })
+        ));
+
+        // skipped lines aren't checked
+        let limits = Limits {
+            max_name_len: Some(8),
+            skip_comment_lines: true,
+            ..Limits::default()
+        };
+        let socket = DummySocket::new(b"# a comment longer than a name\nrequest=smtpd_access_policy\n\n");
+        handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &limits).unwrap();
+        assert_eq!(socket.get_output(), b"action=DEFER\n\n");
+    }
+
+    #[test]
+    fn test_handle_connection_respond_on_truncated_request() {
+        let input = b"request=smtpd_access_policy\n\nrequest=smtpd_access_policy\nclient_address=1.2.3.4\n";
+        let socket = DummySocket::new(input);
+        handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &Limits::default()).unwrap();
+        assert_eq!(socket.get_output(), b"action=DEFER\n\n");
+
+        let limits = Limits {
+            respond_on_truncated_request: true,
+            ..Limits::default()
+        };
+        let socket = DummySocket::new(input);
+        handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &limits).unwrap();
+        assert_eq!(socket.get_output(), b"action=DEFER\n\naction=DEFER 1.2.3.4\n\n");
+
+        let socket = DummySocket::new(b"request=smtpd_access_policy\n");
+        socket.fail_flush(ErrorKind::BrokenPipe);
+        handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &limits).unwrap();
+    }
+
+    #[test]
+    fn test_handle_connection_on_handler_error() {
+        let limits = Limits {
+            on_handler_error: OnError::Respond(PolicyResponse::Defer(b"4.3.0 try again later".to_vec())),
+            ..Limits::default()
+        };
+        let input = b"request=smtpd_access_policy\nclient_address=fail\nclient_address=1.2.3.4\n\n\
+        request=smtpd_access_policy\nclient_address=1.2.3.4\n\n";
+        let socket = DummySocket::new(input);
+        handle_connection_with_limits::<FailingRequestHandler, _, _, _>(&socket, &(), &limits).unwrap();
+        assert_eq!(
+            socket.get_output(),
+            b"action=DEFER 4.3.0 try again later\n\naction=DEFER 1.2.3.4\n\n"
+        );
+
+        let input = b"request=smtpd_access_policy\nclient_address=fail-later\n\n\
+        request=smtpd_access_policy\nclient_address=1.2.3.4\n\n";
+        let socket = DummySocket::new(input);
+        handle_connection_with_limits::<FailingRequestHandler, _, _, _>(&socket, &(), &limits).unwrap();
+        assert_eq!(
+            socket.get_output(),
+            b"action=DEFER 4.3.0 try again later\n\naction=DEFER 1.2.3.4\n\n"
+        );
+
+        let socket = DummySocket::new(input);
+        assert!(matches!(
+            handle_connection_with_limits::<FailingRequestHandler, _, _, _>(&socket, &(), &Limits::default()),
+            Err(PostfixPolicyError::HandlerError(()))
+        ));
+        assert_eq!(socket.get_output(), b"");
+    }
+
+    #[test]
+    fn test_handle_connection_max_request_duration() {
+        let input = b"request=smtpd_access_policy\nclient_address=1.2.3.4\n\nrequest=smtpd_access_policy\n\n";
+        let limits = Limits {
+            max_request_duration: Some(Duration::from_millis(100)),
+            ..Limits::default()
+        };
+        let clock = FakeClock::new();
+        let socket = DummySocket::new(input);
+        socket.slow_reads(2, &clock, Duration::from_millis(5));
+        assert!(matches!(
+            handle_connection_with_metrics::<DummyRequestHandler, _, _, _, _, _>(
+                &socket,
+                &(),
+                &limits,
+                &mut NoMetrics,
+                &clock
+            ),
+            Err(PostfixPolicyError::LimitExceeded {
+                limit: LimitKind::MaxRequestDuration
+            })
+        ));
+        assert_eq!(socket.get_output(), b"");
+
+        let clock = FakeClock::new();
+        let socket = DummySocket::new(input);
+        socket.slow_reads(32, &clock, Duration::from_millis(5));
+        handle_connection_with_metrics::<DummyRequestHandler, _, _, _, _, _>(
+            &socket,
+            &(),
+            &limits,
+            &mut NoMetrics,
+            &clock,
+        )
+        .unwrap();
+        assert_eq!(socket.get_output(), b"action=DEFER 1.2.3.4\n\naction=DEFER\n\n");
+    }
+
+    #[derive(Default)]
+    struct CountingWriter {
+        writes: usize,
+        output: Vec<u8>,
+    }
+    impl Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.writes += 1;
+            self.output.write(buf)
+        }
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> std::io::Result<usize> {
+            self.writes += 1;
+            self.output.write_vectored(bufs)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
         }
     }
 
-    impl<'lt> Write for &DummySocket<'lt> {
-        fn write(&mut self, buf: &[u8]) -> std::result::Result<usize, std::io::Error> {
-            self.output.borrow_mut().write(buf)
+    /// Writer without vectored IO, accepting at most 4 bytes per write.
+    #[derive(Default)]
+    struct ShortWriter {
+        writes: usize,
+        output: Vec<u8>,
+    }
+    impl Write for ShortWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.writes += 1;
+            self.output.write(&buf[..buf.len().min(4)])
         }
-        fn flush(&mut self) -> std::result::Result<(), std::io::Error> {
-            self.output.borrow_mut().flush()
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
         }
     }
 
-    /// Helper function to test [`PolicyRequestHandler`] implementations using the line format.
-    /// Expects `input` to contain one (or more) policy requests. \
-    /// Will use `ctx` as context parameter in `handle_connection`. \
-    /// If `handle_connection` returns successfully, it returns `Ok(..)` containing the complete response(s) that would be sent to the mail server. \
-    /// If `handle_connection` fails, the Error response will be passed through and the response(s) up to that point are discarded.
-    /// ## Example
-    /// ```norun
-    /// let input = b"request=smtpd_access_policy\nprotocol_state=RCPT\n...\n\n";
-    /// assert_eq!(
-    ///     handle_connection_response::<MyRequestHandler, _, _>(input, &()).unwrap(),
-    ///     b"action=DEFER some_message\n\n"
-    /// );
-    /// ```
-    ///
-    /// [`PolicyRequestHandler`]: ../trait.PolicyRequestHandler.html
-    pub fn handle_connection_response<'l, HandlerType, ContextType, ErrorType>(
-        input: &'l [u8],
-        ctx: &'l ContextType,
-    ) -> Result<Vec<u8>, PostfixPolicyError<ErrorType>>
-    where
-        HandlerType: PolicyRequestHandler<'l, ContextType, ErrorType>,
-    {
+    #[test]
+    fn test_flush_response_without_vectored_io() {
+        let mut writer = ShortWriter::default();
+        flush_response::<_, ()>(&mut writer, PolicyResponse::Defer(b"131.234.189.14".to_vec())).unwrap();
+        assert_eq!(writer.output, b"action=DEFER 131.234.189.14\n\n");
+        assert_eq!(writer.writes, 8);
+    }
+
+    #[test]
+    fn test_flush_response() {
+        let mut writer = CountingWriter::default();
+        flush_response::<_, ()>(&mut writer, PolicyResponse::Defer(b"131.234.189.14".to_vec())).unwrap();
+        assert_eq!(writer.output, b"action=DEFER 131.234.189.14\n\n");
+        assert_eq!(writer.writes, 1);
+
+        let input = Cursor::new(&b"request=smtpd_access_policy\nclient_address=1.2.3.4\n\n"[..]);
+        let mut writer = CountingWriter::default();
+        handle_connection_split::<DummyRequestHandler, _, _, _, _>(input, &mut writer, &()).unwrap();
+        assert_eq!(writer.writes, 1);
+
+        let mut writer = CountingWriter::default();
+        assert!(matches!(
+            flush_response::<_, ()>(&mut writer, PolicyResponse::Reject(b"a\nb".to_vec())),
+            Err(PostfixPolicyError::InvalidResponse(
+                ResponseError::MessageContainsNewline(_)
+            ))
+        ));
+        assert_eq!(writer.writes, 0);
+    }
+
+    #[test]
+    fn test_handle_connection_skip_comment_lines() {
+        let input = b"# policy client v1.0\nrequest=smtpd_access_policy\nclient_address=1.2.3.4\n\n";
         let socket = DummySocket::new(input);
-        handle_connection::<HandlerType, ContextType, ErrorType, _>(&socket, ctx)?;
-        Ok(socket.get_output())
+        assert!(matches!(
+            handle_connection::<DummyRequestHandler, _, _, _>(&socket, &()),
+            Err(PostfixPolicyError::ProtocolError(ref line)) if line == b"# policy client v1.0\n"
+        ));
+
+        let limits = Limits {
+            skip_comment_lines: true,
+            ..Limits::default()
+        };
+        let socket = DummySocket::new(input);
+        handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &limits).unwrap();
+        assert_eq!(socket.get_output(), b"action=DEFER 1.2.3.4\n\n");
+
+        let socket = DummySocket::new(b"# comment\nrequest=smtpd_access_policy\ngarbage\n\n");
+        assert!(matches!(
+            handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &limits),
+            Err(PostfixPolicyError::ProtocolError(ref line)) if line == b"garbage\n"
+        ));
     }
-}
 
-#[cfg(test)]
-mod tests {
+    #[test]
+    fn test_split_responses() {
+        let input = b"request=smtpd_access_policy\nprotocol_state=RCPT\nclient_address=131.234.189.14\n\n\
+        client_address=1.2.3.4\n\nrequest=smtpd_access_policy\n\n";
+        let output = handle_connection_response::<DummyRequestHandler, _, _>(input, &()).unwrap();
+        assert_eq!(
+            split_responses(&output),
+            vec![
+                PolicyResponse::Defer(b"131.234.189.14".to_vec()),
+                PolicyResponse::Reject(Vec::new()),
+                PolicyResponse::Defer(Vec::new()),
+            ]
+        );
+        assert_eq!(split_responses(b""), Vec::new());
+    }
 
-    use super::test_helper::handle_connection_response;
-    use super::{PolicyRequestHandler, PolicyResponse, PostfixPolicyError};
+    #[test]
+    #[should_panic(expected = "isn't followed by an empty line")]
+    fn test_split_responses_missing_terminator() {
+        split_responses(b"action=DUNNO\n\naction=DUNNO\naction=OK\n\n");
+    }
 
-    struct DummyRequestHandler {
-        found_request: bool,
-        client_address: Vec<u8>,
+    #[test]
+    #[should_panic(expected = "unterminated response at byte 14")]
+    fn test_split_responses_trailing_garbage() {
+        split_responses(b"action=DUNNO\n\nxyz");
     }
-    impl<'l> PolicyRequestHandler<'l, (), ()> for DummyRequestHandler {
-        fn new(_: &()) -> Self {
-            Self {
-                found_request: false,
-                client_address: vec![],
-            }
+
+    #[test]
+    fn test_replay_file() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/replay.txt");
+        assert_eq!(
+            replay_file::<DummyRequestHandler, _, _>(path, &()).unwrap(),
+            vec![
+                PolicyResponse::Defer(b"192.0.2.1".to_vec()),
+                PolicyResponse::Defer(Vec::new())
+            ]
+        );
+        assert!(matches!(
+            replay_file::<DummyRequestHandler, _, ()>("testdata/missing.txt", &()),
+            Err(PostfixPolicyError::IoError(_))
+        ));
+    }
+
+    #[test]
+    fn test_limits_builder() {
+        let limits: Limits = Limits::builder()
+            .max_line_len(40)
+            .max_attributes(2)
+            .read_timeout(Duration::from_secs(10))
+            .deliver_empty_values(false)
+            .build();
+        assert_eq!(limits.max_line_len, Some(40));
+        assert_eq!(limits.max_attributes, Some(2));
+        assert_eq!(limits.read_timeout, Some(Duration::from_secs(10)));
+        assert!(!limits.deliver_empty_values);
+        assert_eq!(limits.max_name_len, None);
+
+        let socket = DummySocket::new(b"request=smtpd_access_policy\nclient_address=1.2.3.4\n\n");
+        handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &limits).unwrap();
+        assert_eq!(socket.get_output(), b"action=DEFER 1.2.3.4\n\n");
+    }
+
+    #[test]
+    fn test_handle_connection_max_line_len() {
+        let limits = Limits {
+            max_line_len: Some(26),
+            ..Limits::default()
+        };
+        let clock = FakeClock::new();
+        let socket = DummySocket::new(b"request=smtpd_access_policy\n\n");
+        socket.slow_reads(4, &clock, Duration::ZERO);
+        assert!(matches!(
+            handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &limits),
+            Err(PostfixPolicyError::LimitExceeded {
+                limit: LimitKind::MaxLineLen
+            })
+        ));
+
+        let limits = Limits {
+            max_line_len: Some(27),
+            ..Limits::default()
+        };
+        let socket = DummySocket::new(b"request=smtpd_access_policy\n\n");
+        handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &limits).unwrap();
+        assert_eq!(socket.get_output(), b"action=DEFER\n\n");
+    }
+
+    #[test]
+    fn test_handle_connection_max_attributes() {
+        let limits = Limits {
+            max_attributes: Some(2),
+            ..Limits::default()
+        };
+        let socket = DummySocket::new(b"request=smtpd_access_policy\nclient_address=1.2.3.4\n\n");
+        handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &limits).unwrap();
+        assert_eq!(socket.get_output(), b"action=DEFER 1.2.3.4\n\n");
+
+        let socket = DummySocket::new(b"request=smtpd_access_policy\nclient_address=1.2.3.4\nsender=\n\n");
+        assert!(matches!(
+            handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &limits),
+            Err(PostfixPolicyError::LimitExceeded {
+                limit: LimitKind::MaxAttributes
+            })
+        ));
+        assert_eq!(socket.get_output(), b"");
+    }
+
+    #[test]
+    fn test_handle_connection_read_timeout() {
+        let input = b"request=smtpd_access_policy\n\nrequest=smtpd_access_policy\n\n";
+        let limits = Limits {
+            read_timeout: Some(Duration::from_millis(10)),
+            ..Limits::default()
+        };
+        let clock = FakeClock::new();
+        let socket = DummySocket::new(input);
+        socket.slow_reads(4, &clock, Duration::from_millis(10));
+        handle_connection_with_metrics::<DummyRequestHandler, _, _, _, _, _>(
+            &socket,
+            &(),
+            &limits,
+            &mut NoMetrics,
+            &clock,
+        )
+        .unwrap();
+        assert_eq!(socket.get_output(), b"action=DEFER\n\naction=DEFER\n\n");
+
+        let clock = FakeClock::new();
+        let socket = DummySocket::new(input);
+        socket.slow_reads(4, &clock, Duration::from_millis(11));
+        assert!(matches!(
+            handle_connection_with_metrics::<DummyRequestHandler, _, _, _, _, _>(
+                &socket,
+                &(),
+                &limits,
+                &mut NoMetrics,
+                &clock
+            ),
+            Err(PostfixPolicyError::LimitExceeded {
+                limit: LimitKind::ReadTimeout
+            })
+        ));
+    }
+
+    #[test]
+    fn test_handle_connection_on_bytes_written() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let limits = Limits {
+            on_bytes_written: Some({
+                let written = written.clone();
+                Arc::new(move |bytes: &[u8]| written.lock().unwrap().extend_from_slice(bytes))
+            }),
+            ..Limits::default()
+        };
+        let socket = DummySocket::new(b"request=smtpd_access_policy\nclient_address=1.2.3.4\n\n");
+        handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &limits).unwrap();
+        assert_eq!(written.lock().unwrap().as_slice(), b"action=DEFER 1.2.3.4\n\n");
+        assert_eq!(socket.get_output(), b"action=DEFER 1.2.3.4\n\n");
+    }
+
+    #[test]
+    fn test_handle_connection_newline_terminator() {
+        let limits = Limits {
+            response_terminator: ResponseTerminator::Newline,
+            ..Limits::default()
+        };
+        let socket =
+            DummySocket::new(b"request=smtpd_access_policy\nclient_address=1.2.3.4\n\nrequest=smtpd_access_policy\n\n");
+        handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &limits).unwrap();
+        assert_eq!(socket.get_output(), b"action=DEFER 1.2.3.4\naction=DEFER\n");
+    }
+
+    struct ConnectionEventHandler;
+    impl<'l> PolicyRequestHandler<'l, RefCell<Vec<String>>, ()> for ConnectionEventHandler {
+        fn new(ctx: &'l RefCell<Vec<String>>) -> Self {
+            ctx.borrow_mut().push("request".to_string());
+            Self
         }
-        fn attribute(&mut self, name: &[u8], value: &[u8]) -> Option<()> {
-            match name {
-                b"request" => self.found_request = true,
-                b"client_address" => self.client_address = value.to_vec(),
-                _ => {}
-            }
+        fn attribute(&mut self, _: &[u8], _: &[u8]) -> Option<()> {
             None
         }
-
         fn response(self) -> Result<PolicyResponse, ()> {
-            if !self.found_request {
-                return Ok(PolicyResponse::Reject(Vec::new()));
-            }
-            Ok(PolicyResponse::Defer(self.client_address.clone()))
+            Ok(PolicyResponse::Dunno)
+        }
+        fn on_connection_start(ctx: &'l RefCell<Vec<String>>) {
+            ctx.borrow_mut().push("start".to_string());
+        }
+        fn on_connection_end(ctx: &'l RefCell<Vec<String>>, result: &Result<(), PostfixPolicyError<()>>) {
+            ctx.borrow_mut().push(format!("end {:?}", result));
         }
     }
 
     #[test]
-    fn test_handle_connection_valid() {
-        let input =
-            b"request=smtpd_access_policy\nprotocol_state=RCPT\nprotocol_name=ESMTP\nclient_address=131.234.189.14\n\n";
+    fn test_handle_connection_start_end() {
+        let events = RefCell::new(Vec::new());
+        let socket = DummySocket::new(b"request=smtpd_access_policy\n\nrequest=smtpd_access_policy\n\n");
+        handle_connection::<ConnectionEventHandler, _, _, _>(&socket, &events).unwrap();
+        assert_eq!(socket.get_output(), b"action=DUNNO\n\naction=DUNNO\n\n");
+        // the third handler is created for reading up to EOF
         assert_eq!(
-            handle_connection_response::<DummyRequestHandler, _, _>(input, &()).unwrap(),
-            b"action=DEFER 131.234.189.14\n\n"
+            events.into_inner(),
+            vec!["start", "request", "request", "request", "end Ok(())"]
         );
+
+        let events = RefCell::new(Vec::new());
+        let socket = DummySocket::new(b"garbage\n\n");
+        assert!(handle_connection::<ConnectionEventHandler, _, _, _>(&socket, &events).is_err());
+        let events = events.into_inner();
+        assert_eq!(events[..2], ["start", "request"]);
+        assert!(events[2].starts_with("end Err(ProtocolError("));
+        assert_eq!(events.len(), 3);
     }
 
     #[test]
-    fn test_handle_connection_empty() {
-        let input = b"\n";
+    fn test_handle_connection_drain_after_reject() {
+        let input = b"request=smtpd_access_policy\nclient_address=1.2.3.4\n\n\
+        client_address=1.2.3.4\n\nrequest=smtpd_access_policy\n\n";
+        let limits = Limits {
+            drain_after_reject: true,
+            ..Limits::default()
+        };
+        // DUNNO isn't a rejection, so all requests are answered
+        let log = AttributeLog::default();
+        let socket = DummySocket::new(input);
+        handle_connection_with_limits::<LoggingRequestHandler, _, _, _>(&socket, &log, &limits).unwrap();
+        assert_eq!(socket.get_output(), b"action=DUNNO\n\naction=DUNNO\n\naction=DUNNO\n\n");
+
+        // DummyRequestHandler defers the first request, everything after it is discarded
+        let socket = DummySocket::new(input);
+        handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &limits).unwrap();
+        assert_eq!(socket.get_output(), b"action=DEFER 1.2.3.4\n\n");
+
+        let socket = DummySocket::new(input);
+        handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &Limits::default()).unwrap();
         assert_eq!(
-            handle_connection_response::<DummyRequestHandler, _, _>(input, &()).unwrap(),
-            b"action=REJECT\n\n"
+            socket.get_output(),
+            b"action=DEFER 1.2.3.4\n\naction=REJECT\n\naction=DEFER\n\n"
         );
     }
 
     #[test]
-    fn test_handle_connection_line_without_eq() {
-        let input = b"asdf\n\n";
+    fn test_handle_connection_rewrite_response() {
+        let limits = Limits {
+            rewrite_response: Some(Arc::new(|response| match response {
+                PolicyResponse::Reject(message) => PolicyResponse::Defer(message),
+                response => response,
+            })),
+            on_protocol_error_response: Some(PolicyResponse::Reject(b"broken".to_vec())),
+            ..Limits::default()
+        };
+        // DummyRequestHandler rejects requests without `request=smtpd_access_policy`
+        let socket = DummySocket::new(b"request=smtpd_access_policy\nclient_address=1.2.3.4\n\nsender=\n\ngarbage\n\n");
+        handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &limits).unwrap();
+        assert_eq!(
+            socket.get_output(),
+            b"action=DEFER 1.2.3.4\n\naction=DEFER\n\naction=DEFER broken\n\n"
+        );
+    }
 
-        assert!(
-            match handle_connection_response::<DummyRequestHandler, _, _>(input, &()) {
-                Err(PostfixPolicyError::ProtocolError(l)) => {
-                    assert_eq!(&l, b"asdf\n");
-                    true
+    #[test]
+    fn test_handle_connection_max_skipped_lines() {
+        let limits = Limits {
+            skip_comment_lines: true,
+            max_skipped_lines: Some(3),
+            ..Limits::default()
+        };
+        let mut input = b"request=smtpd_access_policy\n#1\n#2\n#3\n\n".to_vec();
+        let socket = DummySocket::new(&input);
+        handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &limits).unwrap();
+        assert_eq!(socket.get_output(), b"action=DEFER\n\n");
+
+        // the count starts over with every request
+        input.extend_from_slice(b"request=smtpd_access_policy\n");
+        for _ in 0..1000 {
+            input.extend_from_slice(b"# garbage\n");
+        }
+        input.extend_from_slice(b"\n");
+        let socket = DummySocket::new(&input);
+        assert!(matches!(
+            handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &limits),
+            Err(PostfixPolicyError::LimitExceeded {
+                limit: LimitKind::MaxSkippedLines
+            })
+        ));
+        assert_eq!(socket.get_output(), b"action=DEFER\n\n");
+    }
+
+    #[test]
+    fn test_handle_connection_transform_value() {
+        let limits = Limits {
+            transform_value: Some(Arc::new(|name: &[u8], value: &[u8]| {
+                if name == b"sender" {
+                    Some(value.to_ascii_lowercase())
+                } else {
+                    None
                 }
-                _ => false,
-            }
+            })),
+            ..Limits::default()
+        };
+        let log = AttributeLog::default();
+        let socket = DummySocket::new(b"sender=Someone@Example.COM\nrecipient=Other@Example.COM\n\n");
+        handle_connection_with_limits::<LoggingRequestHandler, _, _, _>(&socket, &log, &limits).unwrap();
+        assert_eq!(
+            log.into_inner(),
+            vec![
+                (b"sender".to_vec(), b"someone@example.com".to_vec()),
+                (b"recipient".to_vec(), b"Other@Example.COM".to_vec())
+            ]
         );
     }
 
     #[test]
-    fn test_handle_connection_line_empty_name() {
-        let input = b"=a\n\n";
+    fn test_handle_connection_strict_attribute_names() {
+        let limits = Limits {
+            strict_attribute_names: true,
+            ..Limits::default()
+        };
+        let socket = DummySocket::new(b"request=smtpd_access_policy\nclient_address=1.2.3.4\n\n");
+        handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &limits).unwrap();
+        assert_eq!(socket.get_output(), b"action=DEFER 1.2.3.4\n\n");
 
-        assert!(
-            match handle_connection_response::<DummyRequestHandler, _, _>(input, &()) {
-                Err(PostfixPolicyError::ProtocolError(l)) => {
-                    assert_eq!(&l, b"=a\n");
-                    true
-                }
-                _ => false,
-            }
+        for (input, name) in [
+            (
+                &b"request=smtpd_access_policy\nClient_Address=1.2.3.4\n\n"[..],
+                &b"Client_Address"[..],
+            ),
+            (
+                b"request=smtpd_access_policy\nclient address=1.2.3.4\n\n",
+                b"client address",
+            ),
+        ] {
+            let socket = DummySocket::new(input);
+            assert!(matches!(
+                handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &limits),
+                Err(PostfixPolicyError::InvalidAttributeName(ref n)) if n == name
+            ));
+            let socket = DummySocket::new(input);
+            handle_connection_with_limits::<DummyRequestHandler, _, _, _>(&socket, &(), &Limits::default()).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_handle_connection_trim_value_cr() {
+        let input = b"request=smtpd_access_policy\nclient_address=1.2.3.4\r\nsender=\r\n\n";
+        let ctx = AttributeLog::default();
+        let socket = DummySocket::new(input);
+        handle_connection::<LoggingRequestHandler, _, _, _>(&socket, &ctx).unwrap();
+        assert_eq!(
+            *ctx.borrow(),
+            vec![
+                (b"request".to_vec(), b"smtpd_access_policy".to_vec()),
+                (b"client_address".to_vec(), b"1.2.3.4\r".to_vec()),
+                (b"sender".to_vec(), b"\r".to_vec()),
+            ]
+        );
+
+        let limits = Limits {
+            trim_value_cr: true,
+            ..Limits::default()
+        };
+        let ctx = AttributeLog::default();
+        let socket = DummySocket::new(input);
+        handle_connection_with_limits::<LoggingRequestHandler, _, _, _>(&socket, &ctx, &limits).unwrap();
+        assert_eq!(
+            *ctx.borrow(),
+            vec![
+                (b"request".to_vec(), b"smtpd_access_policy".to_vec()),
+                (b"client_address".to_vec(), b"1.2.3.4".to_vec()),
+                (b"sender".to_vec(), Vec::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_handle_one() {
+        let input = b"request=smtpd_access_policy\nclient_address=1.2.3.4\n\nrequest=smtpd_access_policy\n\n";
+        let mut reader = BufReader::new(&input[..]);
+        let mut output = Vec::new();
+        assert_eq!(
+            handle_one::<DummyRequestHandler, _, _, _, _>(&mut reader, &mut output, &()).unwrap(),
+            Some(PolicyResponse::Defer(b"1.2.3.4".to_vec()))
+        );
+        assert_eq!(output, b"action=DEFER 1.2.3.4\n\n");
+        assert_eq!(
+            handle_one::<DummyRequestHandler, _, _, _, _>(&mut reader, &mut output, &()).unwrap(),
+            Some(PolicyResponse::Defer(Vec::new()))
+        );
+        assert_eq!(
+            handle_one::<DummyRequestHandler, _, _, _, _>(&mut reader, &mut output, &()).unwrap(),
+            None
+        );
+        assert_eq!(output, b"action=DEFER 1.2.3.4\n\naction=DEFER\n\n");
+    }
+
+    #[test]
+    fn test_handle_connection_split() {
+        let input = Cursor::new(&b"request=smtpd_access_policy\nclient_address=1.2.3.4\n\nclient_address=\n\n"[..]);
+        let mut output: Vec<u8> = Vec::new();
+        handle_connection_split::<DummyRequestHandler, _, _, _, _>(input, &mut output, &()).unwrap();
+        assert_eq!(output, b"action=DEFER 1.2.3.4\n\naction=REJECT\n\n");
+    }
+
+    struct NewlineRequestHandler;
+    impl<'l> PolicyRequestHandler<'l, (), ()> for NewlineRequestHandler {
+        fn new(_: &()) -> Self {
+            Self
+        }
+        fn attribute(&mut self, _: &[u8], _: &[u8]) -> Option<()> {
+            None
+        }
+        fn response(self) -> Result<PolicyResponse, ()> {
+            Ok(PolicyResponse::Reject(b"a\n\nb".to_vec()))
+        }
+    }
+
+    #[test]
+    fn test_handle_connection_invalid_response() {
+        let socket = DummySocket::new(b"request=smtpd_access_policy\n\n");
+        assert!(matches!(
+            handle_connection::<NewlineRequestHandler, _, _, _>(&socket, &()),
+            Err(PostfixPolicyError::InvalidResponse(
+                ResponseError::MessageContainsNewline(_)
+            ))
+        ));
+        assert_eq!(socket.get_output(), b"");
+    }
+
+    #[test]
+    fn test_handle_connection_max_lifetime() {
+        let input = b"request=smtpd_access_policy\n\nrequest=smtpd_access_policy\n\nrequest=smtpd_access_policy\n\n";
+        let clock = FakeClock::new();
+        let limits = Limits {
+            max_connection_lifetime: Some(Duration::from_millis(30)),
+            ..Limits::default()
+        };
+        let socket = DummySocket::new(input);
+        assert!(matches!(
+            handle_connection_with_metrics::<SlowRequestHandler, _, _, _, _, _>(
+                &socket,
+                &clock,
+                &limits,
+                &mut NoMetrics,
+                &clock
+            ),
+            Err(PostfixPolicyError::LimitExceeded {
+                limit: LimitKind::MaxConnectionLifetime
+            })
+        ));
+        assert_eq!(socket.get_output(), b"action=DUNNO\n\naction=DUNNO\n\n");
+    }
+
+    struct RawLineRequestHandler<'l> {
+        lines: &'l RefCell<Vec<u8>>,
+    }
+    impl<'l> PolicyRequestHandler<'l, RefCell<Vec<u8>>, ()> for RawLineRequestHandler<'l> {
+        fn new(lines: &'l RefCell<Vec<u8>>) -> Self {
+            Self { lines }
+        }
+        fn attribute(&mut self, _: &[u8], _: &[u8]) -> Option<()> {
+            None
+        }
+        fn response(self) -> Result<PolicyResponse, ()> {
+            Ok(PolicyResponse::Dunno)
+        }
+        fn raw_line(&mut self, line: &[u8]) {
+            self.lines.borrow_mut().extend_from_slice(line);
+        }
+    }
+
+    #[test]
+    fn test_handle_connection_raw_line() {
+        let input =
+            b"request=smtpd_access_policy\nprotocol_state=RCPT\nprotocol_name=ESMTP\nclient_address=131.234.189.14\n\n";
+        let lines = RefCell::new(Vec::new());
+        handle_connection_response::<RawLineRequestHandler, _, _>(input, &lines).unwrap();
+        assert_eq!(lines.into_inner(), &input[..]);
+    }
+
+    #[test]
+    fn test_process_request() {
+        let input =
+            b"request=smtpd_access_policy\nprotocol_state=RCPT\nprotocol_name=ESMTP\nclient_address=131.234.189.14\n\n";
+        assert_eq!(
+            process_request::<DummyRequestHandler, _, _>(input, &()).unwrap(),
+            PolicyResponse::Defer(b"131.234.189.14".to_vec())
+        );
+        assert_eq!(
+            process_request::<DummyRequestHandler, _, _>(b"\n", &()).unwrap(),
+            PolicyResponse::Reject(Vec::new())
         );
+        assert!(matches!(
+            process_request::<DummyRequestHandler, _, _>(b"request=smtpd_access_policy\n", &()),
+            Err(PostfixPolicyError::ProtocolError(ref l)) if l == b"request=smtpd_access_policy\n"
+        ));
+        assert!(matches!(
+            process_request::<DummyRequestHandler, _, _>(b"request=smtpd_access_policy\n\nrequest=x\n\n", &()),
+            Err(PostfixPolicyError::ProtocolError(ref l)) if l == b"request=x\n\n"
+        ));
+        assert!(matches!(
+            process_request::<DummyRequestHandler, _, _>(b"asdf\n\n", &()),
+            Err(PostfixPolicyError::ProtocolError(ref l)) if l == b"asdf\n"
+        ));
+    }
+
+    #[test]
+    fn test_handle_framed_request() {
+        // a length-prefixed frame as an embedding protocol might send it
+        let mut frame = Vec::new();
+        let body = b"request=smtpd_access_policy\nprotocol_state=RCPT\nclient_address=131.234.189.14\n\n";
+        frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        frame.extend_from_slice(body);
+        let (len, rest) = frame.split_at(4);
+        let chunk = &rest[..u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize];
+        assert_eq!(
+            handle_framed_request::<DummyRequestHandler, _, _>(chunk, &()).unwrap(),
+            PolicyResponse::Defer(b"131.234.189.14".to_vec())
+        );
+
+        for chunk in [
+            &b"request=smtpd_access_policy\nclient_address=131.234.189.14\n"[..],
+            b"request=smtpd_access_policy\nclient_address=131.234.189.14",
+        ] {
+            assert_eq!(
+                handle_framed_request::<DummyRequestHandler, _, _>(chunk, &()).unwrap(),
+                PolicyResponse::Defer(b"131.234.189.14".to_vec())
+            );
+        }
+        for chunk in [&b""[..], b"\n"] {
+            assert_eq!(
+                handle_framed_request::<DummyRequestHandler, _, _>(chunk, &()).unwrap(),
+                PolicyResponse::Reject(Vec::new())
+            );
+        }
+        assert!(matches!(
+            handle_framed_request::<DummyRequestHandler, _, _>(b"request=smtpd_access_policy\n\nrequest=x", &()),
+            Err(PostfixPolicyError::ProtocolError(ref l)) if l == b"request=x\n\n"
+        ));
     }
 }
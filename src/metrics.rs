@@ -0,0 +1,65 @@
+use std::time::{Duration, Instant};
+
+/// Source of the current time.
+///
+/// Everything time dependent takes the time from a `Clock`, so tests can supply a clock they control
+/// (e.g. [`test_helper::FakeClock`]).
+///
+/// [`test_helper::FakeClock`]: test_helper/struct.FakeClock.html
+pub trait Clock {
+    /// Returns the current time.
+    fn now(&self) -> Instant;
+}
+
+/// [`Clock`] returning the actual time using `Instant::now`.
+///
+/// [`Clock`]: trait.Clock.html
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Receives measurements from [`handle_connection_with_metrics`]. All methods default to doing nothing.
+///
+/// [`handle_connection_with_metrics`]: fn.handle_connection_with_metrics.html
+pub trait Metrics {
+    /// A request was completed. `protocol_state` is the value of the request's `protocol_state` attribute
+    /// (e.g. `RCPT` or `END-OF-MESSAGE`), or empty if it didn't contain one. It can be used to label the measurements.
+    /// `duration` is the time from reading the first line of the request until its response was flushed.
+    fn on_request_complete(&mut self, _protocol_state: &[u8], _duration: Duration) {}
+    /// A request was completed, reporting how long its phases took. Called right after `on_request_complete`.
+    fn on_request_timings(&mut self, _protocol_state: &[u8], _timings: &RequestTimings) {}
+    /// A request was read completely and is about to be passed to the handler's `response` method. `size_bytes`
+    /// is its size as received, including the line feeds and the empty line ending it. `attribute_count` is the
+    /// number of attributes in it, not counting lines skipped by `Limits::skip_comment_lines` or
+    /// `Limits::max_attributes_then_respond`. This allows spotting unusually large requests without failing them.
+    fn on_request_parsed(&mut self, _size_bytes: usize, _attribute_count: usize) {}
+}
+
+/// How long the phases of handling one request took, see [`Metrics::on_request_timings`].
+/// Together they make up the duration passed to [`Metrics::on_request_complete`].
+///
+/// [`Metrics::on_request_timings`]: trait.Metrics.html#method.on_request_timings
+/// [`Metrics::on_request_complete`]: trait.Metrics.html#method.on_request_complete
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RequestTimings {
+    /// From receiving the first byte of the request until its empty line was read. This includes the time spent
+    /// in the handler's `attribute` method.
+    pub read: Duration,
+    /// Time spent in the handler's `response` method.
+    pub compute: Duration,
+    /// Time spent writing and flushing the response.
+    pub write: Duration,
+}
+
+/// [`Metrics`] implementation discarding all measurements.
+///
+/// [`Metrics`]: trait.Metrics.html
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoMetrics;
+
+impl Metrics for NoMetrics {}
@@ -0,0 +1,132 @@
+use super::{handle_connection, PolicyRequestHandler, PolicyResponse, PostfixPolicyError};
+use std::cell::RefCell;
+use std::io::{Read, Write};
+
+/// Handler for policy requests that produces side data along with each response, e.g. a computed score or a tag for
+/// the caller's own logging, without putting it into the response message.
+///
+/// Works like [`PolicyRequestHandler`], but `response` additionally returns an `Output`, which
+/// [`handle_connection_with_output`] passes to a callback instead of sending it to the server. This is a trait of its
+/// own as associated types can't have defaults yet, so `PolicyRequestHandler` couldn't gain one without breaking
+/// existing handlers.
+///
+/// [`PolicyRequestHandler`]: trait.PolicyRequestHandler.html
+/// [`handle_connection_with_output`]: fn.handle_connection_with_output.html
+pub trait OutputPolicyRequestHandler<'l, ContextType, ErrorType> {
+    /// The side data returned with each response.
+    type Output;
+    /// Creates a new instance and initalizes it with the context `ContextType`.
+    fn new(ctx: &'l ContextType) -> Self;
+    /// Attribute `name` with value `value` was part of the request. If this method returns `Some(error)`,
+    /// handling of the request is cancelled and [`handle_connection_with_output`] will return `Err(error)`.
+    ///
+    /// [`handle_connection_with_output`]: fn.handle_connection_with_output.html
+    fn attribute(&mut self, name: &[u8], value: &[u8]) -> Option<ErrorType>;
+    /// Returns the desired action after all attributes were processed, together with the side data for the caller.
+    /// If this method returns `Err(error)`, [`handle_connection_with_output`] will return `Err(error)`.
+    ///
+    /// [`handle_connection_with_output`]: fn.handle_connection_with_output.html
+    fn response(self) -> Result<(PolicyResponse, Self::Output), ErrorType>;
+}
+
+struct OutputContext<'ctx, ContextType, OnOutputType> {
+    ctx: &'ctx ContextType,
+    on_output: RefCell<OnOutputType>,
+}
+
+struct OutputRequest<'l, 'ctx, ContextType, HandlerType, OnOutputType> {
+    outer: &'l OutputContext<'ctx, ContextType, OnOutputType>,
+    inner: HandlerType,
+}
+
+impl<'l, 'ctx, ContextType, ErrorType, HandlerType, OnOutputType>
+    PolicyRequestHandler<'l, OutputContext<'ctx, ContextType, OnOutputType>, ErrorType>
+    for OutputRequest<'l, 'ctx, ContextType, HandlerType, OnOutputType>
+where
+    HandlerType: OutputPolicyRequestHandler<'ctx, ContextType, ErrorType>,
+    OnOutputType: FnMut(&PolicyResponse, HandlerType::Output),
+{
+    fn new(outer: &'l OutputContext<'ctx, ContextType, OnOutputType>) -> Self {
+        Self {
+            outer,
+            inner: HandlerType::new(outer.ctx),
+        }
+    }
+
+    fn attribute(&mut self, name: &[u8], value: &[u8]) -> Option<ErrorType> {
+        self.inner.attribute(name, value)
+    }
+
+    fn response(self) -> Result<PolicyResponse, ErrorType> {
+        let (response, output) = self.inner.response()?;
+        (self.outer.on_output.borrow_mut())(&response, output);
+        Ok(response)
+    }
+}
+
+/**
+ Handles a connection to the mail server using an [`OutputPolicyRequestHandler`].
+
+ Works like [`handle_connection`]. For every request, `on_output` is called with the response and the side data the
+ handler returned, right before the response is sent to the server.
+
+ [`OutputPolicyRequestHandler`]: trait.OutputPolicyRequestHandler.html
+ [`handle_connection`]: fn.handle_connection.html
+*/
+pub fn handle_connection_with_output<'socket, 'ctx, HandlerType, ContextType, ErrorType, SocketType, OnOutputType>(
+    socket: &'socket SocketType,
+    ctx: &'ctx ContextType,
+    on_output: OnOutputType,
+) -> Result<(), PostfixPolicyError<ErrorType>>
+where
+    HandlerType: OutputPolicyRequestHandler<'ctx, ContextType, ErrorType>,
+    &'socket SocketType: Read + Write,
+    OnOutputType: FnMut(&PolicyResponse, HandlerType::Output),
+{
+    let outer = OutputContext {
+        ctx,
+        on_output: RefCell::new(on_output),
+    };
+    handle_connection::<OutputRequest<ContextType, HandlerType, OnOutputType>, _, ErrorType, _>(socket, &outer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{handle_connection_with_output, OutputPolicyRequestHandler};
+    use crate::test_helper::DummySocket;
+    use crate::PolicyResponse;
+
+    /// Scores a request by the number of attributes with an empty value.
+    struct ScoreHandler {
+        score: u32,
+    }
+
+    impl<'l> OutputPolicyRequestHandler<'l, (), ()> for ScoreHandler {
+        type Output = u32;
+        fn new(_: &()) -> Self {
+            Self { score: 0 }
+        }
+        fn attribute(&mut self, _name: &[u8], value: &[u8]) -> Option<()> {
+            if value.is_empty() {
+                self.score += 1;
+            }
+            None
+        }
+        fn response(self) -> Result<(PolicyResponse, u32), ()> {
+            Ok((PolicyResponse::Dunno, self.score))
+        }
+    }
+
+    #[test]
+    fn test_handle_connection_with_output() {
+        let input = b"request=smtpd_access_policy\nsender=\nhelo_name=\n\nrequest=smtpd_access_policy\n\n";
+        let socket = DummySocket::new(input);
+        let mut outputs = Vec::new();
+        handle_connection_with_output::<ScoreHandler, _, _, _, _>(&socket, &(), |response, score| {
+            outputs.push((response.clone(), score))
+        })
+        .unwrap();
+        assert_eq!(outputs, vec![(PolicyResponse::Dunno, 2), (PolicyResponse::Dunno, 0)]);
+        assert_eq!(socket.get_output(), b"action=DUNNO\n\naction=DUNNO\n\n");
+    }
+}
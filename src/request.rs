@@ -0,0 +1,516 @@
+use super::PolicyResponse;
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::IpAddr;
+
+/// The SMTP protocol stage a policy request was made in, as sent in the `protocol_state` attribute.
+///
+/// Postfix sends one request for each stage the policy is configured for in the corresponding
+/// `smtpd_*_restrictions`. States Postfix might add in the future are kept as `Other`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolState {
+    Connect,
+    Ehlo,
+    Helo,
+    Mail,
+    Rcpt,
+    Data,
+    EndOfMessage,
+    Vrfy,
+    Etrn,
+    Other(Vec<u8>),
+}
+
+impl ProtocolState {
+    /// Parses the value of a `protocol_state` attribute.
+    pub fn parse(value: &[u8]) -> ProtocolState {
+        match value {
+            b"CONNECT" => ProtocolState::Connect,
+            b"EHLO" => ProtocolState::Ehlo,
+            b"HELO" => ProtocolState::Helo,
+            b"MAIL" => ProtocolState::Mail,
+            b"RCPT" => ProtocolState::Rcpt,
+            b"DATA" => ProtocolState::Data,
+            b"END-OF-MESSAGE" => ProtocolState::EndOfMessage,
+            b"VRFY" => ProtocolState::Vrfy,
+            b"ETRN" => ProtocolState::Etrn,
+            other => ProtocolState::Other(other.to_vec()),
+        }
+    }
+
+    /// Returns `true` for `END-OF-MESSAGE`, the request sent after the message content was received
+    /// (for `smtpd_end_of_data_restrictions`).
+    ///
+    /// This is the final stage of a mail transaction: all recipients are known and the message `size` is final, so
+    /// it is the place for checks that should only run once per message.
+    pub fn is_end_of_message(&self) -> bool {
+        *self == ProtocolState::EndOfMessage
+    }
+}
+
+/// The SASL mechanism a client authenticated with, as sent in the `sasl_method` attribute.
+///
+/// Mechanisms without their own variant are kept as `Other`, in upper case.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SaslMethod {
+    /// The client didn't authenticate, `sasl_method` is empty.
+    None,
+    Plain,
+    Login,
+    CramMd5,
+    DigestMd5,
+    GssApi,
+    Other(Vec<u8>),
+}
+
+impl SaslMethod {
+    /// Parses the value of a `sasl_method` attribute. Mechanism names are matched case-insensitively.
+    pub fn from_bytes(value: &[u8]) -> SaslMethod {
+        match &value.to_ascii_uppercase()[..] {
+            b"" => SaslMethod::None,
+            b"PLAIN" => SaslMethod::Plain,
+            b"LOGIN" => SaslMethod::Login,
+            b"CRAM-MD5" => SaslMethod::CramMd5,
+            b"DIGEST-MD5" => SaslMethod::DigestMd5,
+            b"GSSAPI" => SaslMethod::GssApi,
+            other => SaslMethod::Other(other.to_vec()),
+        }
+    }
+
+    /// Returns `true` for mechanisms sending the password itself (`PLAIN` and `LOGIN`), which should only be
+    /// accepted over an encrypted connection.
+    pub fn is_plaintext(&self) -> bool {
+        matches!(self, SaslMethod::Plain | SaslMethod::Login)
+    }
+}
+
+/// Parses an IP address in any of the forms Postfix passes them in.
+///
+/// Postfix prefixes IPv6 client addresses with `ipv6:` (e.g. `ipv6:2001:db8::1`), and addresses in `helo_name`
+/// are sent as address literals like `[192.0.2.1]` or `[IPv6:2001:db8::1]`. The brackets and the prefix (in any
+/// case) are stripped before parsing, so all of these compare equal to the bare address. Returns `None` for anything
+/// that isn't an address, like a host name.
+pub fn normalize_and_parse_ip(value: &[u8]) -> Option<IpAddr> {
+    let value = match value {
+        [b'[', inner @ .., b']'] => inner,
+        _ => value,
+    };
+    let value = match value.get(..5) {
+        Some(prefix) if prefix.eq_ignore_ascii_case(b"ipv6:") => &value[5..],
+        _ => value,
+    };
+    std::str::from_utf8(value).ok()?.parse().ok()
+}
+
+/// Parses a numeric attribute value like `size` or `recipient_count`.
+///
+/// Only plain decimal digits are accepted. Returns `None` for empty values, signs, whitespace and values that don't
+/// fit into an `u64`.
+pub fn parse_u64(value: &[u8]) -> Option<u64> {
+    if value.is_empty() {
+        return None;
+    }
+    value.iter().try_fold(0u64, |number, &c| {
+        if !c.is_ascii_digit() {
+            return None;
+        }
+        number.checked_mul(10)?.checked_add(u64::from(c - b'0'))
+    })
+}
+
+/// Identifies a request in the Postfix logs, see [`ParsedRequest::correlation_id`].
+///
+/// Displayed as `queue_id/instance`, e.g. `4Bq1Xz0sLqz9sTR/1a2b.3c`. A missing queue id is shown as `NOQUEUE`
+/// like in the Postfix logs (there is none before the first recipient was accepted), a missing instance as `-`.
+///
+/// [`ParsedRequest::correlation_id`]: struct.ParsedRequest.html#method.correlation_id
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CorrelationId<'a> {
+    /// The `queue_id` attribute, `None` if it is empty or missing.
+    pub queue_id: Option<&'a [u8]>,
+    /// The `instance` attribute, `None` if it is empty or missing.
+    pub instance: Option<&'a [u8]>,
+}
+
+impl fmt::Display for CorrelationId<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let queue_id = self.queue_id.unwrap_or(b"NOQUEUE");
+        let instance = self.instance.unwrap_or(b"-");
+        write!(
+            f,
+            "{}/{}",
+            String::from_utf8_lossy(queue_id),
+            String::from_utf8_lossy(instance)
+        )
+    }
+}
+
+/// The attributes of a complete policy request, in the order they were received.
+///
+/// Handlers that want to look at the request as a whole can collect it by calling [`push`] from their
+/// `attribute` method and evaluate it in `response`.
+///
+/// All occurrences of an attribute are kept. Postfix sends every attribute once, but other clients (and
+/// multi-recipient checks) may send `recipient` repeatedly, which is why [`recipients`] returns a list. Use
+/// [`get_all`] for other attributes that may repeat.
+///
+/// [`push`]: #method.push
+/// [`recipients`]: #method.recipients
+/// [`get_all`]: #method.get_all
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ParsedRequest {
+    attributes: Vec<(Vec<u8>, Vec<u8>)>,
+    recipients: Vec<Vec<u8>>,
+}
+
+impl ParsedRequest {
+    /// Creates an empty request.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends the attribute `name` with value `value`.
+    pub fn push(&mut self, name: &[u8], value: &[u8]) {
+        if name == b"recipient" && !value.is_empty() {
+            self.recipients.push(value.to_vec());
+        }
+        self.attributes.push((name.to_vec(), value.to_vec()));
+    }
+
+    /// Returns the value of the first attribute called `name`, or `None` if the request doesn't contain it.
+    pub fn get(&self, name: &[u8]) -> Option<&[u8]> {
+        self.iter().find(|(n, _)| *n == name).map(|(_, value)| value)
+    }
+
+    /// Iterates over the values of all attributes called `name`, in the order they were received.
+    pub fn get_all<'a>(&'a self, name: &'a [u8]) -> impl Iterator<Item = &'a [u8]> + 'a {
+        self.iter().filter(move |(n, _)| *n == name).map(|(_, value)| value)
+    }
+
+    /// Returns all non-empty `recipient` values in the order they were received.
+    ///
+    /// Postfix sends a single recipient per request, and an empty one in stages after `RCPT` when the message has
+    /// several recipients. Requests from multi-recipient checks may contain the attribute repeatedly, so unlike
+    /// [`get`] this doesn't stop at the first one.
+    ///
+    /// [`get`]: #method.get
+    pub fn recipients(&self) -> &[Vec<u8>] {
+        &self.recipients
+    }
+
+    /// Returns the envelope sender address given in `MAIL FROM`, or `None` if it is empty or the request doesn't
+    /// contain it. The null sender `<>` of bounces is also reported as `None`.
+    ///
+    /// This is the address claimed by the client and must not be confused with [`sasl_sender`].
+    ///
+    /// [`sasl_sender`]: #method.sasl_sender
+    pub fn sender(&self) -> Option<&[u8]> {
+        self.get_non_empty(b"sender")
+    }
+
+    /// Returns the sender address given by an authenticated client using the `AUTH=` parameter of `MAIL FROM`,
+    /// or `None` if it is empty or the request doesn't contain it.
+    ///
+    /// This is distinct from the envelope sender returned by [`sender`]; checks like SPF or DMARC have to use
+    /// [`sender`].
+    ///
+    /// [`sender`]: #method.sender
+    pub fn sasl_sender(&self) -> Option<&[u8]> {
+        self.get_non_empty(b"sasl_sender")
+    }
+
+    /// Returns the parsed `client_address`, see [`normalize_and_parse_ip`]. `None` if it is missing or `unknown`.
+    ///
+    /// [`normalize_and_parse_ip`]: fn.normalize_and_parse_ip.html
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        normalize_and_parse_ip(self.get(b"client_address")?)
+    }
+
+    /// Returns the `queue_id` and `instance` of this request, to find the matching lines in the Postfix logs.
+    pub fn correlation_id(&self) -> CorrelationId<'_> {
+        CorrelationId {
+            queue_id: self.get_non_empty(b"queue_id"),
+            instance: self.get_non_empty(b"instance"),
+        }
+    }
+
+    /// Returns the mechanism the client authenticated with. `SaslMethod::None` if it didn't authenticate, which is
+    /// also reported if the request doesn't contain `sasl_method` at all.
+    pub fn sasl_method(&self) -> SaslMethod {
+        SaslMethod::from_bytes(self.get(b"sasl_method").unwrap_or_default())
+    }
+
+    /// Returns `true` if the client connection is encrypted with TLS, i.e. `encryption_protocol` is non-empty.
+    pub fn is_encrypted(&self) -> bool {
+        self.get_non_empty(b"encryption_protocol").is_some()
+    }
+
+    /// Returns the number of bits of the TLS cipher in use (`encryption_keysize`), or `None` if the connection
+    /// isn't encrypted or the value isn't a number as accepted by [`parse_u64`].
+    ///
+    /// [`parse_u64`]: fn.parse_u64.html
+    pub fn encryption_keysize(&self) -> Option<u32> {
+        u32::try_from(parse_u64(self.get_non_empty(b"encryption_keysize")?)?).ok()
+    }
+
+    /// Checks that the client connection is encrypted with a cipher of at least `min_bits` bits.
+    ///
+    /// Returns `None` if it is, so the request can be evaluated further. Otherwise returns a suggested rejection,
+    /// `530 5.7.0` as Postfix itself uses when TLS is enforced.
+    pub fn requires_tls(&self, min_bits: u32) -> Option<PolicyResponse> {
+        let text: &[u8] = if !self.is_encrypted() {
+            b"5.7.0 Must issue a STARTTLS command first"
+        } else if self.encryption_keysize().unwrap_or(0) < min_bits {
+            b"5.7.0 Encryption too weak"
+        } else {
+            return None;
+        };
+        Some(PolicyResponse::RejectCoded {
+            code: Some(530),
+            text: text.to_vec(),
+        })
+    }
+
+    /// Returns the protocol stage given in `protocol_state`, or `None` if it is empty or the request doesn't
+    /// contain it.
+    pub fn protocol_state(&self) -> Option<ProtocolState> {
+        self.get_non_empty(b"protocol_state").map(ProtocolState::parse)
+    }
+
+    /// Returns `true` if this request was made in the final stage of the mail transaction, `END-OF-MESSAGE`.
+    /// See [`ProtocolState::is_end_of_message`].
+    ///
+    /// [`ProtocolState::is_end_of_message`]: enum.ProtocolState.html#method.is_end_of_message
+    pub fn is_final_stage(&self) -> bool {
+        self.protocol_state().is_some_and(|state| state.is_end_of_message())
+    }
+
+    fn get_non_empty(&self, name: &[u8]) -> Option<&[u8]> {
+        self.get(name).filter(|value| !value.is_empty())
+    }
+
+    /// Encodes the request in the form it is sent on the wire: a `name=value` line per attribute in order, followed
+    /// by the empty line ending the request. Useful to feed the request to another handler or policy server.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for (name, value) in self.iter() {
+            bytes.extend_from_slice(name);
+            bytes.push(b'=');
+            bytes.extend_from_slice(value);
+            bytes.push(b'\n');
+        }
+        bytes.push(b'\n');
+        bytes
+    }
+
+    /// Iterates over all `(name, value)` pairs in the order they were received.
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &[u8])> {
+        self.attributes
+            .iter()
+            .map(|(name, value)| (name.as_slice(), value.as_slice()))
+    }
+
+    /// Iterates over all `(name, value)` pairs whose name starts with `prefix`, e.g. `b"sasl_"`.
+    pub fn iter_prefix<'a>(&'a self, prefix: &'a [u8]) -> impl Iterator<Item = (&'a [u8], &'a [u8])> + 'a {
+        self.iter().filter(move |(name, _)| name.starts_with(prefix))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalize_and_parse_ip, parse_u64, CorrelationId, ParsedRequest, ProtocolState, SaslMethod};
+    use crate::PolicyResponse;
+
+    const SAMPLE: &[u8] = b"request=smtpd_access_policy\nprotocol_state=RCPT\nsasl_method=PLAIN\n\
+    client_address=192.0.2.1\nsasl_username=user\nsasl_sender=\n\n";
+
+    fn sample() -> ParsedRequest {
+        let mut request = ParsedRequest::new();
+        for line in SAMPLE.split(|&c| c == b'\n').take_while(|line| !line.is_empty()) {
+            let pos = line.iter().position(|&c| c == b'=').unwrap();
+            request.push(&line[..pos], &line[pos + 1..]);
+        }
+        request
+    }
+
+    #[test]
+    fn test_get() {
+        let request = sample();
+        assert_eq!(request.get(b"protocol_state"), Some(&b"RCPT"[..]));
+        assert_eq!(request.get(b"sasl_sender"), Some(&b""[..]));
+        assert_eq!(request.get(b"recipient"), None);
+    }
+
+    #[test]
+    fn test_recipients() {
+        let mut request = ParsedRequest::new();
+        request.push(b"request", b"smtpd_access_policy");
+        request.push(b"recipient", b"a@example.com");
+        request.push(b"recipient", b"");
+        request.push(b"recipient", b"b@example.com");
+        assert_eq!(
+            request.recipients(),
+            &[b"a@example.com".to_vec(), b"b@example.com".to_vec()][..]
+        );
+        assert_eq!(request.get(b"recipient"), Some(&b"a@example.com"[..]));
+        assert_eq!(request.get_all(b"recipient").count(), 3);
+        assert!(sample().recipients().is_empty());
+    }
+
+    #[test]
+    fn test_iter_prefix() {
+        let request = sample();
+        let sasl: Vec<_> = request.iter_prefix(b"sasl_").collect();
+        assert_eq!(
+            sasl,
+            vec![
+                (&b"sasl_method"[..], &b"PLAIN"[..]),
+                (&b"sasl_username"[..], &b"user"[..]),
+                (&b"sasl_sender"[..], &b""[..]),
+            ]
+        );
+        assert_eq!(request.iter_prefix(b"ccert_").count(), 0);
+    }
+
+    #[test]
+    fn test_sender_and_sasl_sender() {
+        let mut request = sample();
+        assert_eq!(request.sender(), None);
+        assert_eq!(request.sasl_sender(), None);
+
+        request.push(b"sender", b"claimed@example.com");
+        assert_eq!(request.sender(), Some(&b"claimed@example.com"[..]));
+        assert_eq!(request.sasl_sender(), None);
+
+        let mut request = ParsedRequest::new();
+        request.push(b"sender", b"claimed@example.com");
+        request.push(b"sasl_sender", b"authenticated@example.com");
+        assert_eq!(request.sender(), Some(&b"claimed@example.com"[..]));
+        assert_eq!(request.sasl_sender(), Some(&b"authenticated@example.com"[..]));
+    }
+
+    #[test]
+    fn test_requires_tls() {
+        let unencrypted = sample();
+        assert!(!unencrypted.is_encrypted());
+        assert_eq!(unencrypted.encryption_keysize(), None);
+        assert_eq!(
+            unencrypted.requires_tls(128),
+            Some(PolicyResponse::RejectCoded {
+                code: Some(530),
+                text: b"5.7.0 Must issue a STARTTLS command first".to_vec(),
+            })
+        );
+
+        let mut weak = sample();
+        weak.push(b"encryption_protocol", b"TLSv1");
+        weak.push(b"encryption_cipher", b"DES-CBC3-SHA");
+        weak.push(b"encryption_keysize", b"56");
+        assert!(weak.is_encrypted());
+        assert_eq!(weak.encryption_keysize(), Some(56));
+        assert_eq!(
+            weak.requires_tls(128),
+            Some(PolicyResponse::RejectCoded {
+                code: Some(530),
+                text: b"5.7.0 Encryption too weak".to_vec(),
+            })
+        );
+
+        let mut strong = sample();
+        strong.push(b"encryption_protocol", b"TLSv1.3");
+        strong.push(b"encryption_cipher", b"TLS_AES_256_GCM_SHA384");
+        strong.push(b"encryption_keysize", b"256");
+        assert!(strong.is_encrypted());
+        assert_eq!(strong.encryption_keysize(), Some(256));
+        assert_eq!(strong.requires_tls(128), None);
+
+        let mut signed = sample();
+        signed.push(b"encryption_protocol", b"TLSv1.3");
+        signed.push(b"encryption_keysize", b"+256");
+        assert_eq!(signed.encryption_keysize(), None);
+    }
+
+    #[test]
+    fn test_to_bytes() {
+        assert_eq!(sample().to_bytes(), SAMPLE);
+        assert_eq!(ParsedRequest::new().to_bytes(), b"\n");
+    }
+
+    #[test]
+    fn test_protocol_state() {
+        let rcpt = sample();
+        assert_eq!(rcpt.protocol_state(), Some(ProtocolState::Rcpt));
+        assert!(!rcpt.is_final_stage());
+
+        let mut end_of_message = ParsedRequest::new();
+        end_of_message.push(b"protocol_state", b"END-OF-MESSAGE");
+        assert_eq!(end_of_message.protocol_state(), Some(ProtocolState::EndOfMessage));
+        assert!(end_of_message.is_final_stage());
+
+        assert_eq!(ProtocolState::parse(b"BDAT"), ProtocolState::Other(b"BDAT".to_vec()));
+        assert_eq!(ParsedRequest::new().protocol_state(), None);
+        assert!(!ParsedRequest::new().is_final_stage());
+    }
+
+    #[test]
+    fn test_normalize_and_parse_ip() {
+        let v6: std::net::IpAddr = "2001:db8::1".parse().unwrap();
+        let v4: std::net::IpAddr = "192.0.2.1".parse().unwrap();
+        assert_eq!(normalize_and_parse_ip(b"ipv6:2001:db8::1"), Some(v6));
+        assert_eq!(normalize_and_parse_ip(b"[IPv6:2001:db8::1]"), Some(v6));
+        assert_eq!(normalize_and_parse_ip(b"[192.0.2.1]"), Some(v4));
+        assert_eq!(normalize_and_parse_ip(b"192.0.2.1"), Some(v4));
+        assert_eq!(normalize_and_parse_ip(b"[192.0.2.1"), None);
+        assert_eq!(normalize_and_parse_ip(b"mail.example.com"), None);
+        assert_eq!(sample().client_ip(), Some(v4));
+    }
+
+    #[test]
+    fn test_parse_u64() {
+        assert_eq!(parse_u64(b"0"), Some(0));
+        assert_eq!(parse_u64(b"10240"), Some(10240));
+        assert_eq!(parse_u64(b"18446744073709551615"), Some(u64::MAX));
+        assert_eq!(parse_u64(b"18446744073709551616"), None);
+        for value in [&b""[..], b"+1", b"-1", b" 1", b"1 ", b"1k"] {
+            assert_eq!(parse_u64(value), None, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn test_correlation_id() {
+        let mut request = ParsedRequest::new();
+        request.push(b"queue_id", b"4Bq1Xz0sLqz9sTR");
+        request.push(b"instance", b"1a2b.3c");
+        assert_eq!(
+            request.correlation_id(),
+            CorrelationId {
+                queue_id: Some(b"4Bq1Xz0sLqz9sTR"),
+                instance: Some(b"1a2b.3c")
+            }
+        );
+        assert_eq!(request.correlation_id().to_string(), "4Bq1Xz0sLqz9sTR/1a2b.3c");
+
+        let mut request = ParsedRequest::new();
+        request.push(b"queue_id", b"");
+        request.push(b"instance", b"1a2b.3c");
+        assert_eq!(request.correlation_id().queue_id, None);
+        assert_eq!(request.correlation_id().to_string(), "NOQUEUE/1a2b.3c");
+
+        assert_eq!(ParsedRequest::new().correlation_id().to_string(), "NOQUEUE/-");
+    }
+
+    #[test]
+    fn test_sasl_method() {
+        assert_eq!(SaslMethod::from_bytes(b"PLAIN"), SaslMethod::Plain);
+        assert_eq!(SaslMethod::from_bytes(b"cram-md5"), SaslMethod::CramMd5);
+        assert_eq!(SaslMethod::from_bytes(b""), SaslMethod::None);
+        assert_eq!(
+            SaslMethod::from_bytes(b"scram-sha-256"),
+            SaslMethod::Other(b"SCRAM-SHA-256".to_vec())
+        );
+        assert!(SaslMethod::Login.is_plaintext());
+        assert!(!SaslMethod::GssApi.is_plaintext());
+        assert_eq!(sample().sasl_method(), SaslMethod::Plain);
+        assert_eq!(ParsedRequest::new().sasl_method(), SaslMethod::None);
+    }
+}
@@ -0,0 +1,395 @@
+use super::{handle_connection_with_metrics, Limits, Metrics, PolicyRequestHandler, PostfixPolicyError, SystemClock};
+use std::cell::Cell;
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::net::{SocketAddr, UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::thread;
+
+thread_local! {
+    static CURRENT_ID: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+/// Describes a connection accepted by [`serve_unix`], so measurements and errors can be attributed to a listener.
+///
+/// [`serve_unix`]: fn.serve_unix.html
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    /// Number of the connection, counting from 1 in the order [`serve_unix`] accepted them. Include it in log lines
+    /// to tell concurrent connections apart.
+    ///
+    /// [`serve_unix`]: fn.serve_unix.html
+    pub id: u64,
+    /// Path of the socket the connection was accepted on. Identifies the listener if there are several.
+    pub local_path: PathBuf,
+    /// Address of the connecting peer. For Postfix this is usually unnamed.
+    pub peer: SocketAddr,
+}
+
+impl ConnectionInfo {
+    /// Returns the `id` of the connection handled by the calling thread, or `None` if it isn't a connection thread
+    /// of [`serve_unix`]. This makes the id available to handlers, whose context is shared by all connections.
+    ///
+    /// [`serve_unix`]: fn.serve_unix.html
+    pub fn current_id() -> Option<u64> {
+        CURRENT_ID.with(Cell::get)
+    }
+}
+
+/**
+ Binds a Unix socket at `path` and handles every connection to it on its own thread.
+
+ Each connection is handled like [`handle_connection_with_metrics`], configured by `limits`. `new_metrics` is called
+ for every accepted connection with its [`ConnectionInfo`] and returns the [`Metrics`] receiving that connection's
+ measurements, so they can be labeled with the listener they arrived on. If handling a connection fails, `on_error`
+ is called with the error and the connection is closed. The `id` in the `ConnectionInfo` tells which connection
+ failed; handlers can get it with [`ConnectionInfo::current_id`].
+
+ If [`Limits::read_timeout`] is set, it is set as the read timeout of every connection, so a peer stalling within
+ a request can't block its thread forever.
+
+ Runs until accepting a connection fails (returning that error) or [`Limits::should_stop`] is set. The flag is
+ checked after each accepted connection, so a blocked `accept` only notices it with the next connection.
+ Before returning, all connections still open are waited for.
+
+ The socket file is removed again when this returns, and when unwinding from a panic (a panic in a connection thread
+ is raised once accepting stops). It is left behind if the process is killed without unwinding, e.g. by `SIGKILL` or by a `SIGTERM` without a
+ handler, so a file remaining at `path` from an earlier run still makes binding fail.
+
+ [`handle_connection_with_metrics`]: fn.handle_connection_with_metrics.html
+ [`ConnectionInfo`]: struct.ConnectionInfo.html
+ [`ConnectionInfo::current_id`]: struct.ConnectionInfo.html#method.current_id
+ [`Metrics`]: trait.Metrics.html
+ [`Limits::read_timeout`]: struct.Limits.html#structfield.read_timeout
+ [`Limits::should_stop`]: struct.Limits.html#structfield.should_stop
+*/
+pub fn serve_unix<'ctx, HandlerType, ContextType, ErrorType, MetricsType, NewMetricsType, OnErrorType>(
+    path: impl AsRef<Path>,
+    ctx: &'ctx ContextType,
+    limits: &Limits<ErrorType>,
+    new_metrics: NewMetricsType,
+    on_error: OnErrorType,
+) -> std::io::Result<()>
+where
+    HandlerType: PolicyRequestHandler<'ctx, ContextType, ErrorType>,
+    ContextType: Sync,
+    MetricsType: Metrics,
+    NewMetricsType: Fn(&ConnectionInfo) -> MetricsType + Sync,
+    OnErrorType: Fn(&ConnectionInfo, PostfixPolicyError<ErrorType>) + Sync,
+{
+    let local_path = path.as_ref().to_path_buf();
+    let listener = UnixListener::bind(&local_path)?;
+    let _socket_file = SocketFileGuard(&local_path);
+    let (new_metrics, on_error) = (&new_metrics, &on_error);
+    let mut last_id = 0;
+
+    thread::scope(|scope| loop {
+        let (conn, peer) = listener.accept()?;
+        if let Some(should_stop) = &limits.should_stop {
+            if should_stop.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+        }
+        last_id += 1;
+        let info = ConnectionInfo {
+            id: last_id,
+            local_path: local_path.clone(),
+            peer,
+        };
+        scope.spawn(move || {
+            CURRENT_ID.with(|id| id.set(Some(info.id)));
+            if let Err(e) = conn.set_read_timeout(limits.read_timeout) {
+                on_error(&info, e.into());
+                return;
+            }
+            let mut metrics = new_metrics(&info);
+            let result = handle_connection_with_metrics::<HandlerType, _, _, _, _, _>(
+                &TimeoutStream(&conn),
+                ctx,
+                limits,
+                &mut metrics,
+                &SystemClock,
+            );
+            if let Err(e) = result {
+                on_error(&info, e);
+            }
+        });
+    })
+}
+
+/// A connection with the read timeout of `Limits::read_timeout` set, reporting its expiry as `ErrorKind::TimedOut`.
+///
+/// Unix sockets report an expired read timeout as `WouldBlock`, which the connection handling can't tell apart from a
+/// non-blocking socket having no data.
+struct TimeoutStream<'l>(&'l UnixStream);
+
+impl Read for &TimeoutStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match { self.0 }.read(buf) {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Err(ErrorKind::TimedOut.into()),
+            result => result,
+        }
+    }
+}
+
+impl Write for &TimeoutStream<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        { self.0 }.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        { self.0 }.flush()
+    }
+}
+
+/// Removes the socket file at the path when dropped.
+struct SocketFileGuard<'l>(&'l Path);
+
+impl Drop for SocketFileGuard<'_> {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{serve_unix, ConnectionInfo};
+    use crate::test_helper::send_request;
+    use crate::{Limits, Metrics, PolicyRequestHandler, PolicyResponse};
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    struct DunnoHandler;
+    impl<'l> PolicyRequestHandler<'l, (), ()> for DunnoHandler {
+        fn new(_: &()) -> Self {
+            Self
+        }
+        fn attribute(&mut self, _: &[u8], _: &[u8]) -> Option<()> {
+            None
+        }
+        fn response(self) -> Result<PolicyResponse, ()> {
+            Ok(PolicyResponse::Dunno)
+        }
+    }
+
+    type Recorded = Arc<Mutex<Vec<(PathBuf, Vec<u8>)>>>;
+
+    struct ListenerMetrics {
+        local_path: PathBuf,
+        recorded: Recorded,
+    }
+    impl Metrics for ListenerMetrics {
+        fn on_request_complete(&mut self, protocol_state: &[u8], _duration: Duration) {
+            let entry = (self.local_path.clone(), protocol_state.to_vec());
+            self.recorded.lock().unwrap().push(entry);
+        }
+    }
+
+    fn wait_for_socket(path: &PathBuf) -> UnixStream {
+        loop {
+            if let Ok(stream) = UnixStream::connect(path) {
+                return stream;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_serve_unix_metrics_local_path() {
+        let path = std::env::temp_dir().join(format!("postfix_policy_serve_{}.sock", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let stop = Arc::new(AtomicBool::new(false));
+        let limits = Limits {
+            should_stop: Some(stop.clone()),
+            ..Limits::default()
+        };
+        let recorded = Recorded::default();
+
+        let server = {
+            let (path, recorded) = (path.clone(), recorded.clone());
+            std::thread::spawn(move || {
+                let new_metrics = |info: &ConnectionInfo| ListenerMetrics {
+                    local_path: info.local_path.clone(),
+                    recorded: recorded.clone(),
+                };
+                serve_unix::<DunnoHandler, _, _, _, _, _>(&path, &(), &limits, new_metrics, |_, e| {
+                    panic!("connection failed: {:?}", e)
+                })
+            })
+        };
+
+        let mut stream = wait_for_socket(&path);
+        let response = send_request(
+            &mut stream,
+            &[(b"request", b"smtpd_access_policy"), (b"protocol_state", b"RCPT")],
+        );
+        assert_eq!(response.unwrap(), PolicyResponse::Dunno);
+        drop(stream);
+
+        stop.store(true, Ordering::SeqCst);
+        UnixStream::connect(&path).unwrap();
+        server.join().unwrap().unwrap();
+        assert!(!path.exists());
+        assert_eq!(recorded.lock().unwrap().clone(), vec![(path, b"RCPT".to_vec())]);
+    }
+
+    /// Records the id of the connection it runs in, then fails for requests with a `fail` attribute.
+    struct IdHandler {
+        fail: bool,
+    }
+    impl<'l> PolicyRequestHandler<'l, Mutex<Vec<Option<u64>>>, ()> for IdHandler {
+        fn new(ids: &'l Mutex<Vec<Option<u64>>>) -> Self {
+            ids.lock().unwrap().push(ConnectionInfo::current_id());
+            Self { fail: false }
+        }
+        fn attribute(&mut self, name: &[u8], _: &[u8]) -> Option<()> {
+            self.fail |= name == b"fail";
+            None
+        }
+        fn response(self) -> Result<PolicyResponse, ()> {
+            if self.fail {
+                return Err(());
+            }
+            Ok(PolicyResponse::Dunno)
+        }
+    }
+
+    #[test]
+    fn test_serve_unix_connection_ids() {
+        let path = std::env::temp_dir().join(format!("postfix_policy_serve_ids_{}.sock", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let stop = Arc::new(AtomicBool::new(false));
+        let limits = Limits {
+            should_stop: Some(stop.clone()),
+            ..Limits::default()
+        };
+        let ids = Arc::new(Mutex::new(Vec::new()));
+        let failed = Arc::new(Mutex::new(Vec::new()));
+
+        let server = {
+            let (path, ids, failed) = (path.clone(), ids.clone(), failed.clone());
+            std::thread::spawn(move || {
+                let new_metrics = |_: &ConnectionInfo| crate::NoMetrics;
+                serve_unix::<IdHandler, _, _, _, _, _>(&path, &*ids, &limits, new_metrics, |info, _| {
+                    failed.lock().unwrap().push(info.id)
+                })
+            })
+        };
+
+        for _ in 0..2 {
+            let mut stream = wait_for_socket(&path);
+            let response = send_request(&mut stream, &[(b"request", b"smtpd_access_policy")]);
+            assert_eq!(response.unwrap(), PolicyResponse::Dunno);
+            assert!(send_request(&mut stream, &[(b"fail", b"yes")]).is_err());
+        }
+
+        stop.store(true, Ordering::SeqCst);
+        UnixStream::connect(&path).unwrap();
+        server.join().unwrap().unwrap();
+        assert_eq!(*ids.lock().unwrap(), vec![Some(1), Some(1), Some(2), Some(2)]);
+        let mut failed = failed.lock().unwrap().clone();
+        failed.sort();
+        assert_eq!(failed, vec![1, 2]);
+        assert_eq!(ConnectionInfo::current_id(), None);
+    }
+
+    #[test]
+    fn test_serve_unix_read_timeout() {
+        let path = std::env::temp_dir().join(format!("postfix_policy_serve_timeout_{}.sock", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let stop = Arc::new(AtomicBool::new(false));
+        let limits = Limits {
+            should_stop: Some(stop.clone()),
+            read_timeout: Some(Duration::from_millis(50)),
+            ..Limits::default()
+        };
+        let failed = Arc::new(Mutex::new(Vec::new()));
+
+        let server = {
+            let (path, failed) = (path.clone(), failed.clone());
+            std::thread::spawn(move || {
+                let new_metrics = |_: &ConnectionInfo| crate::NoMetrics;
+                serve_unix::<DunnoHandler, _, _, _, _, _>(&path, &(), &limits, new_metrics, |info, e| {
+                    failed.lock().unwrap().push((info.id, format!("{:?}", e)))
+                })
+            })
+        };
+
+        // idle connections aren't affected
+        let mut stream = wait_for_socket(&path);
+        std::thread::sleep(Duration::from_millis(120));
+        let response = send_request(&mut stream, &[(b"request", b"smtpd_access_policy")]);
+        assert_eq!(response.unwrap(), PolicyResponse::Dunno);
+
+        // a peer stalling within a request is cut off without sending anything more
+        stream.write_all(b"request=smtpd_access_policy\n").unwrap();
+        let mut buf = [0; 1];
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+
+        stop.store(true, Ordering::SeqCst);
+        UnixStream::connect(&path).unwrap();
+        server.join().unwrap().unwrap();
+        assert_eq!(
+            *failed.lock().unwrap(),
+            vec![(1, "LimitExceeded { limit: ReadTimeout }".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_serve_unix_removes_socket_on_panic() {
+        let path = std::env::temp_dir().join(format!("postfix_policy_serve_panic_{}.sock", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let stop = Arc::new(AtomicBool::new(false));
+        let limits = Limits {
+            should_stop: Some(stop.clone()),
+            ..Limits::default()
+        };
+        let server = {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                let new_metrics = |_: &ConnectionInfo| -> ListenerMetrics { panic!("no metrics today") };
+                serve_unix::<DunnoHandler, _, _, _, _, _>(&path, &(), &limits, new_metrics, |_, _| ())
+            })
+        };
+
+        // the panic of the connection thread is raised once serve_unix stops accepting
+        let mut stream = wait_for_socket(&path);
+        let mut buf = [0; 1];
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        stop.store(true, Ordering::SeqCst);
+        UnixStream::connect(&path).unwrap();
+        assert!(server.join().is_err());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_send_request_unix_socket() {
+        use crate::handle_connection;
+        use std::os::unix::net::UnixListener;
+
+        let path = std::env::temp_dir().join(format!("postfix_policy_send_{}.sock", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = std::thread::spawn(move || {
+            let (conn, _) = listener.accept().unwrap();
+            handle_connection::<DunnoHandler, _, _, _>(&conn, &()).unwrap();
+        });
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let response = send_request(
+            &mut stream,
+            &[(b"request", b"smtpd_access_policy"), (b"client_address", b"192.0.2.1")],
+        )
+        .unwrap();
+        assert_eq!(response, PolicyResponse::Dunno);
+        let response = send_request(&mut stream, &[(b"request", b"smtpd_access_policy")]).unwrap();
+        assert_eq!(response, PolicyResponse::Dunno);
+        drop(stream);
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,246 @@
+use super::{handle_connection, PolicyRequestHandler, PolicyResponse, PostfixPolicyError};
+use std::cell::RefCell;
+use std::io::{Read, Write};
+
+/// Handler for policy requests that is kept alive for all requests of one SMTP transaction.
+///
+/// Postfix sends a policy request for every protocol stage it is configured for (`RCPT`, `END-OF-MESSAGE`, ...).
+/// All requests belonging to the same message transaction carry the same `instance` attribute.
+/// [`handle_connection_sessioned`] calls `new` for the first request of a transaction and reuses that instance for
+/// all following requests with the same `instance`. Requests without an `instance` attribute always get a new instance.
+/// This allows e.g. following the `recipient_count` growing across the `RCPT` requests of a message, as done by the
+/// `max_recipients` example.
+///
+/// An error returned by `attribute` or `response` doesn't end the transaction: the instance is kept with whatever
+/// state it had reached, and is reused if the connection goes on to handle further requests of the transaction.
+///
+/// [`handle_connection_sessioned`]: fn.handle_connection_sessioned.html
+pub trait SessionPolicyRequestHandler<'l, ContextType, ErrorType> {
+    /// Creates a new instance and initalizes it with the context `ContextType`.
+    fn new(ctx: &'l ContextType) -> Self;
+    /// Attribute `name` with value `value` was part of the current request. If this method returns `Some(error)`,
+    /// handling of the request is cancelled and [`handle_connection_sessioned`] will return `Err(error)`.
+    ///
+    /// [`handle_connection_sessioned`]: fn.handle_connection_sessioned.html
+    fn attribute(&mut self, name: &[u8], value: &[u8]) -> Option<ErrorType>;
+    /// Returns the desired action for the current request. `protocol_state` is the value of the current request's
+    /// `protocol_state` attribute (e.g. `RCPT` or `END-OF-MESSAGE`), or empty if the request didn't contain one.
+    /// If this method returns `Err(error)`, [`handle_connection_sessioned`] will return `Err(error)`.
+    ///
+    /// [`handle_connection_sessioned`]: fn.handle_connection_sessioned.html
+    fn response(&mut self, protocol_state: &[u8]) -> Result<PolicyResponse, ErrorType>;
+}
+
+struct Session<'ctx, ContextType, HandlerType> {
+    ctx: &'ctx ContextType,
+    current: RefCell<Option<(Vec<u8>, HandlerType)>>,
+}
+
+/// Collects the attributes of one request, as the `instance` attribute deciding which handler they belong to
+/// usually arrives late in the request.
+struct SessionRequest<'l, 'ctx, ContextType, HandlerType> {
+    session: &'l Session<'ctx, ContextType, HandlerType>,
+    attributes: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl<'l, 'ctx, ContextType, ErrorType, HandlerType>
+    PolicyRequestHandler<'l, Session<'ctx, ContextType, HandlerType>, ErrorType>
+    for SessionRequest<'l, 'ctx, ContextType, HandlerType>
+where
+    HandlerType: SessionPolicyRequestHandler<'ctx, ContextType, ErrorType>,
+{
+    fn new(session: &'l Session<'ctx, ContextType, HandlerType>) -> Self {
+        Self {
+            session,
+            attributes: Vec::new(),
+        }
+    }
+
+    fn attribute(&mut self, name: &[u8], value: &[u8]) -> Option<ErrorType> {
+        self.attributes.push((name.to_vec(), value.to_vec()));
+        None
+    }
+
+    fn response(self) -> Result<PolicyResponse, ErrorType> {
+        let find = |wanted: &[u8]| {
+            self.attributes
+                .iter()
+                .find(|(name, _)| name == wanted)
+                .map(|(_, value)| value.as_slice())
+                .unwrap_or_default()
+        };
+        let instance = find(b"instance");
+        let protocol_state = find(b"protocol_state");
+
+        let mut current = self.session.current.borrow_mut();
+        let mut handler = match current.take() {
+            Some((current_instance, handler)) if !instance.is_empty() && current_instance == instance => handler,
+            _ => HandlerType::new(self.session.ctx),
+        };
+        let result = match self
+            .attributes
+            .iter()
+            .find_map(|(name, value)| handler.attribute(name, value))
+        {
+            Some(error) => Err(error),
+            None => handler.response(protocol_state),
+        };
+        *current = Some((instance.to_vec(), handler));
+        result
+    }
+}
+
+/**
+ Handles a connection to the mail server using a [`SessionPolicyRequestHandler`].
+
+ Works like [`handle_connection`], but keeps the handler instance for all requests of the same SMTP transaction
+ (identified by the `instance` attribute) instead of creating a new one for every request.
+ The attributes of each request are buffered until the request is complete, as only then it is known
+ which transaction it belongs to.
+
+ [`SessionPolicyRequestHandler`]: trait.SessionPolicyRequestHandler.html
+ [`handle_connection`]: fn.handle_connection.html
+*/
+pub fn handle_connection_sessioned<'socket, 'ctx, HandlerType, ContextType, ErrorType, SocketType>(
+    socket: &'socket SocketType,
+    ctx: &'ctx ContextType,
+) -> Result<(), PostfixPolicyError<ErrorType>>
+where
+    HandlerType: SessionPolicyRequestHandler<'ctx, ContextType, ErrorType>,
+    &'socket SocketType: Read + Write,
+{
+    let session: Session<ContextType, HandlerType> = Session {
+        ctx,
+        current: RefCell::new(None),
+    };
+    handle_connection::<SessionRequest<ContextType, HandlerType>, _, ErrorType, _>(socket, &session)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{handle_connection_sessioned, Session, SessionPolicyRequestHandler, SessionRequest};
+    use crate::test_helper::DummySocket;
+    use crate::{PolicyRequestHandler, PolicyResponse};
+    use std::cell::RefCell;
+
+    struct StageHandler {
+        requests: usize,
+        recipient: Vec<u8>,
+    }
+
+    impl<'l> SessionPolicyRequestHandler<'l, (), ()> for StageHandler {
+        fn new(_: &()) -> Self {
+            Self {
+                requests: 0,
+                recipient: Vec::new(),
+            }
+        }
+        fn attribute(&mut self, name: &[u8], value: &[u8]) -> Option<()> {
+            if name == b"fail" {
+                return Some(());
+            }
+            if name == b"recipient" && !value.is_empty() {
+                self.recipient = value.to_vec();
+            }
+            None
+        }
+        fn response(&mut self, protocol_state: &[u8]) -> Result<PolicyResponse, ()> {
+            self.requests += 1;
+            match protocol_state {
+                b"RCPT" => Ok(PolicyResponse::Dunno),
+                b"END-OF-MESSAGE" => {
+                    let mut message = format!("{} ", self.requests).into_bytes();
+                    message.extend_from_slice(&self.recipient);
+                    Ok(PolicyResponse::Hold(message))
+                }
+                _ => Ok(PolicyResponse::Reject(self.requests.to_string().into_bytes())),
+            }
+        }
+    }
+
+    fn run(input: &[u8]) -> Vec<u8> {
+        let socket = DummySocket::new(input);
+        handle_connection_sessioned::<StageHandler, _, _, _>(&socket, &()).unwrap();
+        socket.get_output()
+    }
+
+    #[test]
+    fn test_sessioned_distinct_states() {
+        let input = b"request=smtpd_access_policy\nprotocol_state=RCPT\nrecipient=a@b.c\ninstance=1a2b.3c\n\n\
+        request=smtpd_access_policy\nprotocol_state=END-OF-MESSAGE\nrecipient=\ninstance=1a2b.3c\n\n";
+        assert_eq!(run(input), b"action=DUNNO\n\naction=HOLD 2 a@b.c\n\n");
+    }
+
+    #[test]
+    fn test_sessioned_new_instance() {
+        let input = b"protocol_state=RCPT\ninstance=1\n\nprotocol_state=DATA\ninstance=2\n\nprotocol_state=DATA\n\n";
+        assert_eq!(run(input), b"action=DUNNO\n\naction=REJECT 1\n\naction=REJECT 1\n\n");
+    }
+
+    #[test]
+    fn test_sessioned_keeps_instance_on_error() {
+        let session: Session<(), StageHandler> = Session {
+            ctx: &(),
+            current: RefCell::new(None),
+        };
+        let request = |attributes: &[(&[u8], &[u8])]| {
+            let mut request: SessionRequest<(), StageHandler> = PolicyRequestHandler::new(&session);
+            for (name, value) in attributes {
+                assert_eq!(request.attribute(name, value), None);
+            }
+            request.response()
+        };
+        let rcpt = request(&[
+            (b"protocol_state", b"RCPT"),
+            (b"recipient", b"a@b.c"),
+            (b"instance", b"1"),
+        ]);
+        assert_eq!(rcpt, Ok(PolicyResponse::Dunno));
+        let failed = request(&[(b"protocol_state", b"RCPT"), (b"fail", b""), (b"instance", b"1")]);
+        assert_eq!(failed, Err(()));
+        let eom = request(&[(b"protocol_state", b"END-OF-MESSAGE"), (b"instance", b"1")]);
+        assert_eq!(eom, Ok(PolicyResponse::Hold(b"2 a@b.c".to_vec())));
+    }
+
+    struct RecipientCapHandler<'l> {
+        max: &'l usize,
+        rcpt_requests: usize,
+        recipient_count: usize,
+    }
+
+    impl<'l> SessionPolicyRequestHandler<'l, usize, ()> for RecipientCapHandler<'l> {
+        fn new(max: &'l usize) -> Self {
+            Self {
+                max,
+                rcpt_requests: 0,
+                recipient_count: 0,
+            }
+        }
+        fn attribute(&mut self, name: &[u8], value: &[u8]) -> Option<()> {
+            if name == b"recipient_count" {
+                self.recipient_count = std::str::from_utf8(value).unwrap().parse().unwrap();
+            }
+            None
+        }
+        fn response(&mut self, _protocol_state: &[u8]) -> Result<PolicyResponse, ()> {
+            self.rcpt_requests += 1;
+            if self.recipient_count >= *self.max {
+                return Ok(PolicyResponse::Reject(self.rcpt_requests.to_string().into_bytes()));
+            }
+            Ok(PolicyResponse::Dunno)
+        }
+    }
+
+    #[test]
+    fn test_sessioned_recipient_count() {
+        let input = b"protocol_state=RCPT\nrecipient_count=0\ninstance=7\n\n\
+        protocol_state=RCPT\nrecipient_count=1\ninstance=7\n\n\
+        protocol_state=RCPT\nrecipient_count=2\ninstance=7\n\n";
+        let socket = DummySocket::new(input);
+        handle_connection_sessioned::<RecipientCapHandler, _, _, _>(&socket, &2).unwrap();
+        assert_eq!(
+            socket.get_output(),
+            b"action=DUNNO\n\naction=DUNNO\n\naction=REJECT 3\n\n"
+        );
+    }
+}